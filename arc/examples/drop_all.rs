@@ -0,0 +1,54 @@
+//! cargo run --release --example drop_all
+//! 比较逐个drop和Arc::drop_all，分别是同一个Arc的一万个clone，和一万个互不相同的Arc
+use std::{
+    hint::black_box,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use arc::Arc;
+
+const N: usize = 10_000;
+const ROUNDS: u32 = 200;
+
+fn measure(make: impl Fn() -> Vec<Arc<u64>>, drop_vec: impl Fn(Vec<Arc<u64>>)) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let v = black_box(make());
+        let start = Instant::now();
+        drop_vec(v);
+        total += start.elapsed();
+    }
+    total / ROUNDS
+}
+
+fn compare(name: &str, make: impl Fn() -> Vec<Arc<u64>> + Copy) {
+    let each = measure(make, drop);
+    let all = measure(make, Arc::drop_all);
+    println!("{name}: drop {each:?}, drop_all {all:?}");
+}
+
+fn main() {
+    let shared = Arc::new(0);
+    compare("10k clones of one Arc", || vec![shared.clone(); N]);
+    compare("10k distinct Arcs", || {
+        (0..N as u64).map(Arc::new).collect()
+    });
+
+    // 另外几个线程同时在clone/drop同一个Arc，逐个drop的每次fetch_sub都要抢cache line
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    drop(black_box(shared.clone()));
+                }
+            });
+        }
+        compare("10k clones of one Arc, 3 threads contending", || {
+            vec![shared.clone(); N]
+        });
+        stop.store(true, Ordering::Relaxed);
+    });
+}
@@ -0,0 +1,77 @@
+//! 只共享Arc里某一个字段的引用，同时让整个分配一直活着(类似owning_ref的做法)
+use std::{ops::Deref, ptr::NonNull};
+
+use crate::third::Arc;
+
+pub struct ArcRef<T, U> {
+    // 只是为了让分配活着，本身不会再被访问
+    _owner: Arc<T>,
+    ptr: NonNull<U>,
+}
+
+// ArcRef共享了T(通过Arc)，也共享了&U
+unsafe impl<T: Send + Sync, U: Sync> Send for ArcRef<T, U> {}
+unsafe impl<T: Send + Sync, U: Sync> Sync for ArcRef<T, U> {}
+
+impl<T> Arc<T> {
+    pub fn map_ref<U>(arc: Arc<T>, f: impl FnOnce(&T) -> &U) -> ArcRef<T, U> {
+        // 数据在堆上，Arc被move也不会移动，所以指针在_owner存活期间一直有效
+        let ptr = NonNull::from(f(&arc));
+        ArcRef { _owner: arc, ptr }
+    }
+}
+
+impl<T, U> Deref for ArcRef<T, U> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: ptr borrows from the data kept alive by _owner,
+        // and nobody can get a &mut to it while an Arc is shared.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, U> Clone for ArcRef<T, U> {
+    fn clone(&self) -> Self {
+        Self {
+            _owner: self._owner.clone(),
+            ptr: self.ptr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn map_ref_keeps_parent_alive() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct DetectDrop;
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        struct Config {
+            name: String,
+            _detect: DetectDrop,
+        }
+        let x = Arc::new(Config {
+            name: String::from("hello"),
+            _detect: DetectDrop,
+        });
+        let name = Arc::map_ref(x.clone(), |c| &c.name);
+        drop(x);
+        let t = std::thread::spawn(move || {
+            assert_eq!(*name, "hello");
+            let name2 = name.clone();
+            drop(name);
+            assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+            drop(name2);
+        });
+        t.join().unwrap();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+}
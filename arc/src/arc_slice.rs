@@ -0,0 +1,92 @@
+//! 共享同一个Arc<[T]>分配里的一段，解析的时候很多小片段可以引用同一个缓冲区，不用复制
+use std::ops::{Deref, Range};
+
+use crate::third::Arc;
+
+pub struct ArcSlice<T> {
+    // 整个分配一直活着，只暴露start..end这一段
+    arc: Arc<[T]>,
+    start: usize,
+    end: usize,
+}
+
+impl<T> Arc<[T]> {
+    /// 越界的话和切片下标一样panic
+    pub fn subslice(arc: &Arc<[T]>, range: Range<usize>) -> ArcSlice<T> {
+        // 借切片下标做越界检查
+        let _ = &arc[range.clone()];
+        ArcSlice {
+            arc: arc.clone(),
+            start: range.start,
+            end: range.end,
+        }
+    }
+}
+
+impl<T> ArcSlice<T> {
+    /// range是相对这一段的，还是共享同一个分配
+    pub fn subslice(&self, range: Range<usize>) -> ArcSlice<T> {
+        let _ = &self[range.clone()];
+        ArcSlice {
+            arc: self.arc.clone(),
+            start: self.start + range.start,
+            end: self.start + range.end,
+        }
+    }
+}
+
+impl<T> Deref for ArcSlice<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        &self.arc[self.start..self.end]
+    }
+}
+
+impl<T> Clone for ArcSlice<T> {
+    fn clone(&self) -> Self {
+        Self {
+            arc: self.arc.clone(),
+            start: self.start,
+            end: self.end,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn subslices_keep_buffer_alive() {
+        let buf = Arc::from_slice(b"GET /index.html");
+        let w = Arc::downgrade(&buf);
+        let method = Arc::subslice(&buf, 0..3);
+        let path = Arc::subslice(&buf, 4..15);
+        drop(buf);
+        let a = thread::spawn(move || {
+            assert_eq!(&*method, b"GET");
+            assert_eq!(&*method.subslice(1..3), b"ET");
+        });
+        let b = thread::spawn(move || {
+            let name = path.subslice(1..6);
+            drop(path);
+            assert_eq!(&*name, b"index");
+            name
+        });
+        a.join().unwrap();
+        let name = b.join().unwrap();
+        // 还有一个子片段活着，缓冲区就还在
+        assert!(w.upgrade().is_some());
+        drop(name);
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn subslice_out_of_range_panics() {
+        let buf = Arc::from_slice(&[1, 2, 3]);
+        Arc::subslice(&buf, 2..4);
+    }
+}
@@ -0,0 +1,45 @@
+//! 每个类型一份共享的默认值：第一次get的时候构造T::default()，之后都返回同一个Arc的clone
+use std::{
+    any::{Any, TypeId},
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::Mutex,
+};
+
+use crate::{shared_once::SharedOnce, third::Arc};
+
+// 泛型函数里不能有依赖T的static，所以按TypeId找到每个类型自己的SharedOnce，
+// SharedOnce是leak出来的，程序结束前一直有效
+static REGISTRY: Mutex<BTreeMap<TypeId, &'static (dyn Any + Send + Sync)>> =
+    Mutex::new(BTreeMap::new());
+
+pub struct DefaultArc<T>(PhantomData<T>);
+
+impl<T: Default + Send + Sync + 'static> DefaultArc<T> {
+    pub fn get() -> Arc<T> {
+        let once = *REGISTRY
+            .lock()
+            .unwrap()
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::leak(Box::new(SharedOnce::<T>::new())));
+        // 注册表的锁在这之前已经释放了，T::default里再调用DefaultArc也不会死锁
+        once.downcast_ref::<SharedOnce<T>>()
+            .unwrap()
+            .get_or_init(T::default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get_shares_one_allocation() {
+        let a = DefaultArc::<String>::get();
+        let b = DefaultArc::<String>::get();
+        assert!(std::ptr::eq(&*a, &*b));
+        assert_eq!(*a, "");
+        let c = DefaultArc::<Vec<u8>>::get();
+        assert!(c.is_empty());
+    }
+}
@@ -0,0 +1,80 @@
+//! 观察者模式：总线只持有回调的Weak，订阅者自己拿着Arc，订阅者没了回调也就跟着失效，不会泄漏
+use std::sync::Mutex;
+
+use crate::third::{Arc, Weak};
+
+/// 这里的Arc不支持dyn，所以多包一层Box
+pub type Callback<E> = Box<dyn Fn(&E) + Send + Sync>;
+
+pub struct EventBus<E> {
+    subscribers: Mutex<Vec<Weak<Callback<E>>>>,
+}
+
+impl<E> EventBus<E> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 返回的Arc就是订阅本身，drop掉它就等于取消订阅
+    pub fn subscribe(&self, f: impl Fn(&E) + Send + Sync + 'static) -> Arc<Callback<E>> {
+        let callback = Arc::new(Box::new(f) as Callback<E>);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&callback));
+        callback
+    }
+
+    /// 顺便清理掉已经失效的订阅。回调是在锁外面调用的，回调里可以再subscribe
+    pub fn publish(&self, event: &E) {
+        let live: Vec<_> = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            let mut live = Vec::with_capacity(subscribers.len());
+            subscribers.retain(|weak| match weak.upgrade() {
+                Some(callback) => {
+                    live.push(callback);
+                    true
+                }
+                None => false,
+            });
+            live
+        };
+        for callback in &live {
+            callback(event);
+        }
+    }
+}
+
+impl<E> Default for EventBus<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn dropped_subscriber_is_pruned() {
+        static A: AtomicUsize = AtomicUsize::new(0);
+        static B: AtomicUsize = AtomicUsize::new(0);
+        let bus = EventBus::new();
+        let a = bus.subscribe(|n: &usize| {
+            A.fetch_add(*n, Ordering::Relaxed);
+        });
+        let b = bus.subscribe(|n: &usize| {
+            B.fetch_add(*n, Ordering::Relaxed);
+        });
+        bus.publish(&1);
+        drop(b);
+        bus.publish(&10);
+        assert_eq!(A.load(Ordering::Relaxed), 11);
+        assert_eq!(B.load(Ordering::Relaxed), 1);
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        drop(a);
+    }
+}
@@ -2,7 +2,6 @@ use std::{
     ops::Deref,
     ptr::NonNull,
     sync::atomic::{fence, AtomicUsize, Ordering},
-    usize,
 };
 
 struct ArcData<T> {
@@ -27,7 +26,7 @@ unsafe impl<T> Sync for Arc<T> where T: Send + Sync {}
 
 impl<T> Arc<T> {
     pub fn new(data: T) -> Self {
-        /// Box leak保证放弃了排他的所有权，Box new是新分配一个内存
+        // Box leak保证放弃了排他的所有权，Box new是新分配一个内存
         Self {
             ptr: NonNull::from(Box::leak(Box::new(ArcData {
                 ref_count: AtomicUsize::new(1),
@@ -37,7 +36,7 @@ impl<T> Arc<T> {
     }
 
     fn data(&self) -> &ArcData<T> {
-        /// 这里可以使用unsafe的原因是，Arc存在就保证了ptr非空，这时候就可以正常访问
+        // 这里可以使用unsafe的原因是，Arc存在就保证了ptr非空，这时候就可以正常访问
         unsafe {
             self.ptr.as_ref()
         }
@@ -66,11 +65,9 @@ impl<T> Deref for Arc<T> {
 
 impl<T> Clone for Arc<T> {
     fn clone(&self) -> Self {
-        unsafe {
-            // handle overflows
-            if self.data().ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2 {
-                std::process::abort();
-            }
+        // handle overflows
+        if self.data().ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2 {
+            std::process::abort();
         }
         Self { ptr: self.ptr }
     }
@@ -1,3 +1,11 @@
-mod first;
-mod second;
-mod third;
\ No newline at end of file
+pub mod arc_ref;
+pub mod arc_slice;
+pub mod default_arc;
+pub mod event_bus;
+pub mod first;
+pub mod second;
+pub mod shared_once;
+pub mod third;
+pub mod weak_set;
+
+pub use third::{Arc, Weak};
@@ -1,10 +1,9 @@
-///! weak pointer version
+//! weak pointer version
 use std::{
     cell::UnsafeCell,
     ops::Deref,
     ptr::NonNull,
     sync::atomic::{fence, AtomicUsize, Ordering},
-    usize,
 };
 
 struct ArcData<T> {
@@ -0,0 +1,125 @@
+//! 懒初始化的共享全局Arc：第一次get_or_init的时候构造，之后都返回同一个Arc的clone
+use std::{
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use crate::third::{Arc, ArcData};
+
+pub struct SharedOnce<T> {
+    // 非空的时候SharedOnce自己持有这个分配的一个强引用
+    ptr: AtomicPtr<ArcData<T>>,
+    // 和Arc<T>一样的Send/Sync条件
+    _marker: PhantomData<Arc<T>>,
+}
+
+impl<T> SharedOnce<T> {
+    pub const fn new() -> Self {
+        Self {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> Option<Arc<T>> {
+        let p = self.ptr.load(Ordering::Acquire);
+        NonNull::new(p).map(|p| unsafe { Self::clone_from_ptr(p) })
+    }
+
+    /// 多个线程同时初始化的时候只有compare_exchange成功的那个会被保留，
+    /// 其他线程构造出来的值会被drop掉，然后返回赢家的Arc
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> Arc<T> {
+        if let Some(arc) = self.get() {
+            return arc;
+        }
+        // 引用计数为1，这一份交给SharedOnce持有
+        let arc = ManuallyDrop::new(Arc::new(f()));
+        let new = arc.ptr.as_ptr();
+        // Release把数据发布出去，失败的时候Acquire读到赢家的数据
+        let p = match self.ptr.compare_exchange(
+            ptr::null_mut(),
+            new,
+            Ordering::Release,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => new,
+            Err(winner) => {
+                drop(ManuallyDrop::into_inner(arc));
+                winner
+            }
+        };
+        // Safety: p is non-null here.
+        unsafe { Self::clone_from_ptr(NonNull::new_unchecked(p)) }
+    }
+
+    /// Safety: p必须是SharedOnce持有的那个分配
+    unsafe fn clone_from_ptr(p: NonNull<ArcData<T>>) -> Arc<T> {
+        // 借用SharedOnce持有的那一份引用来clone，不能drop它
+        let arc = ManuallyDrop::new(Arc { ptr: p });
+        (*arc).clone()
+    }
+}
+
+impl<T> Default for SharedOnce<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for SharedOnce<T> {
+    fn drop(&mut self) {
+        if let Some(p) = NonNull::new(*self.ptr.get_mut()) {
+            drop(Arc { ptr: p });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        sync::{atomic::AtomicUsize, Barrier},
+        thread,
+    };
+
+    #[test]
+    fn racing_initializers_share_one_arc() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct DetectDrop(usize);
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let once = SharedOnce::new();
+        let inits = AtomicUsize::new(0);
+        let barrier = Barrier::new(8);
+        let arcs: Vec<Arc<DetectDrop>> = thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    let (once, inits, barrier) = (&once, &inits, &barrier);
+                    s.spawn(move || {
+                        barrier.wait();
+                        once.get_or_init(|| {
+                            inits.fetch_add(1, Ordering::Relaxed);
+                            DetectDrop(i)
+                        })
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(arcs.iter().all(|a| ptr::eq(&**a, &*arcs[0])));
+        assert!(ptr::eq(&*once.get().unwrap(), &*arcs[0]));
+        // 输掉的初始化都已经被drop了
+        let inits = inits.load(Ordering::Relaxed);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), inits - 1);
+        let winner = arcs[0].0;
+        drop(arcs);
+        assert_eq!(once.get().unwrap().0, winner);
+        drop(once);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), inits);
+    }
+}
@@ -1,35 +1,58 @@
-///! weak pointer中，clone和drop都需要两次的原子指令操作
-/// 现在来优化
-///
+//! weak pointer中，clone和drop都需要两次的原子指令操作
+//! 现在来优化
 use std::{
+    alloc::{alloc, handle_alloc_error, realloc, Layout},
+    any::Any,
     cell::UnsafeCell,
-    mem::ManuallyDrop,
-    ops::Deref,
-    ptr::NonNull,
+    fmt,
+    hash::{Hash, Hasher},
+    mem::{ManuallyDrop, MaybeUninit},
+    ops::{Deref, DerefMut},
+    ptr::{self, addr_of_mut, NonNull},
+};
+
+#[cfg(not(loom))]
+use std::{
+    hint::spin_loop,
     sync::atomic::{fence, AtomicUsize, Ordering},
 };
-pub struct Arc<T> {
-    ptr: NonNull<ArcData<T>>,
+// 用loom跑模型检查的时候换成它的原子类型
+#[cfg(loom)]
+use loom::{
+    hint::spin_loop,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+pub struct Arc<T: ?Sized> {
+    pub(crate) ptr: NonNull<ArcData<T>>,
 }
 
-unsafe impl<T: Sync + Send> Send for Arc<T> {}
-unsafe impl<T: Sync + Send> Sync for Arc<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Send for Arc<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Sync for Arc<T> {}
 
-pub struct Weak<T> {
+pub struct Weak<T: ?Sized> {
     ptr: NonNull<ArcData<T>>,
 }
-unsafe impl<T: Sync + Send> Send for Weak<T> {}
-unsafe impl<T: Sync + Send> Sync for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Send for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Sync for Weak<T> {}
 
-struct ArcData<T> {
+// repr(C)保证字段按顺序排，from_slice才能自己算出Arc<[T]>的布局
+#[repr(C)]
+pub(crate) struct ArcData<T: ?Sized> {
     /// Number of `Arc`s.
     data_ref_count: AtomicUsize,
     /// Number of `Weak`s, plus one if there are any `Arc`s.
     alloc_ref_count: AtomicUsize,
+    /// 强引用计数的上限，超过就panic，只在debug构建里检查。
+    #[cfg(debug_assertions)]
+    max_strong: usize,
     /// The data. Dropped if there are only weak pointers left.
+    /// 可能是unsized的，所以必须放在最后
     data: UnsafeCell<ManuallyDrop<T>>,
 }
 
+/// get_mut检查的时候 alloc_ref_count 会被短暂换成这个值，期间downgrade需要等待
+const WEAK_LOCKED: usize = usize::MAX;
+
 impl<T> Arc<T> {
     pub fn new(data: T) -> Self {
         Self {
@@ -38,17 +61,432 @@ impl<T> Arc<T> {
                     data_ref_count: AtomicUsize::new(1),
                     alloc_ref_count: AtomicUsize::new(1),
                     data: UnsafeCell::new(ManuallyDrop::new(data)),
+                    #[cfg(debug_assertions)]
+                    max_strong: usize::MAX,
                 }
             }))),
         }
     }
 
+    /// 构造的时候就把指向自己的Weak交给T，这样T在drop的时候可以通过它判断自己是不是最后一个owner。
+    /// f执行期间强引用计数还是0，所以这个Weak还upgrade不了
+    pub fn new_with_weak(f: impl FnOnce(Weak<T>) -> T) -> Self {
+        let ptr = NonNull::from(Box::leak(Box::new(MaybeUninit::<ArcData<T>>::uninit()))).cast();
+        let data: *mut ArcData<T> = ptr.as_ptr();
+        // Safety: The allocation is ours, only the counters are initialized here.
+        unsafe {
+            addr_of_mut!((*data).data_ref_count).write(AtomicUsize::new(0));
+            // 一个给f的Weak，一个是之后所有Arc共同持有的隐式Weak
+            addr_of_mut!((*data).alloc_ref_count).write(AtomicUsize::new(2));
+            #[cfg(debug_assertions)]
+            addr_of_mut!((*data).max_strong).write(usize::MAX);
+        }
+        // f panic的时候由它释放分配，数据还没写进去，不会被drop
+        let implicit = Weak { ptr };
+        let value = f(Weak { ptr });
+        std::mem::forget(implicit);
+        // Safety: Nobody can read the data until data_ref_count becomes non-zero.
+        unsafe {
+            addr_of_mut!((*data).data).write(UnsafeCell::new(ManuallyDrop::new(value)));
+        }
+        // Release matches the Acquire in Weak::upgrade, so upgraders see the data.
+        unsafe { (*data).data_ref_count.store(1, Ordering::Release) };
+        Arc { ptr }
+    }
+
+    /// 测试里用来抓多余的clone：debug构建下同时存在的Arc(包括返回的这一个)超过max_strong个就panic，
+    /// 所以max_strong为2的时候只能再clone一次。release构建下和new完全一样
+    pub fn new_capped(data: T, max_strong: usize) -> Self {
+        assert!(max_strong >= 1, "max_strong counts the Arc being created");
+        let arc = Self::new(data);
+        #[cfg(debug_assertions)]
+        // Safety: Nobody else can see the allocation yet.
+        unsafe {
+            (*arc.ptr.as_ptr()).max_strong = max_strong;
+        }
+        arc
+    }
+
+    /// 只有这一个Arc的时候原地换掉里面的值，把旧值返回；
+    /// 和get_mut一样，还有别的Arc或者Weak的时候返回None，arc不变
+    pub fn replace(arc: &mut Self, value: T) -> Option<T> {
+        Arc::get_mut(arc).map(|data| std::mem::replace(data, value))
+    }
+
+    /// 只剩这一个Arc的时候把数据move出来，否则把arc原样还回去。
+    /// 还有Weak也可以成功，之后它们都upgrade不了了
+    pub fn try_unwrap(arc: Self) -> Result<T, Self> {
+        if arc
+            .data()
+            .data_ref_count
+            .compare_exchange(1, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(arc);
+        }
+        Ok(arc.take_last())
+    }
+
+    /// 和try_unwrap一样，但失败的时候arc也被drop了。
+    /// 多个线程同时对各自的clone调用，正好有一个拿到Some，try_unwrap做不到这一点
+    pub fn into_inner(arc: Self) -> Option<T> {
+        if arc.data().data_ref_count.fetch_sub(1, Ordering::Release) != 1 {
+            // 计数已经减过了，不能再走Drop
+            std::mem::forget(arc);
+            return None;
+        }
+        Some(arc.take_last())
+    }
+
+    /// 强引用计数已经被换成0之后调用，把数据move出来并释放这个Arc占的那份引用
+    fn take_last(self) -> T {
+        // Acquire matches the Release decrement in Arc::drop,
+        // so everything the other Arcs did to the data is visible.
+        fence(Ordering::Acquire);
+        // Safety: The data reference counter is zero, so nothing else will access the data.
+        // ManuallyDrop keeps the allocation from dropping it a second time.
+        let data = unsafe { ManuallyDrop::take(&mut *self.data().data.get()) };
+        // 丢掉所有Arc共同持有的那个隐式的Weak，没有别的Weak的话分配在这里释放
+        drop(Weak {
+            ptr: self.into_ptr(),
+        });
+        data
+    }
+
+    /// builder里反复修改同一个Arc时用的唯一入口，无论Arc是被强引用还是弱引用共享都是正确的:
+    /// - 唯一的Arc并且没有Weak: 直接原地修改
+    /// - 还有别的Arc: clone一份新的，原来的不受影响
+    /// - 只剩Weak: 把数据move到新的分配里，之后那些Weak都upgrade不了了
+    pub fn get_mut_or_clone(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        // 先把强引用计数从1换成0，这样Weak::upgrade就拿不到新的Arc了
+        // Acquire matches the Release decrement in Arc::drop.
+        if arc
+            .data()
+            .data_ref_count
+            .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // 还有别的Arc，clone一份，原来的Arc正常drop
+            *arc = Arc::new((**arc).clone());
+        } else if arc.data().alloc_ref_count.load(Ordering::Acquire) == 1 {
+            // 没有Weak，并且我们拿着&mut，也不会再有新的Weak，恢复计数原地修改
+            // Release matches the Acquire in Weak::upgrade.
+            arc.data().data_ref_count.store(1, Ordering::Release);
+        } else {
+            // 只剩Weak，把数据move出来放进新的分配
+            // Safety: The data reference counter is zero, so nothing else will access the data.
+            let data = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+            let old = std::mem::replace(&mut arc.ptr, Arc::new(data).into_ptr());
+            // 丢掉所有Arc共同持有的那个隐式的Weak
+            drop(Weak { ptr: old });
+        }
+        // Safety: We now hold the only Arc and there are no Weaks pointing to it.
+        unsafe { &mut *arc.data().data.get() }
+    }
+
+    /// 写时复制，和std的Arc::make_mut同名，逻辑就是get_mut_or_clone
+    pub fn make_mut(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        Arc::get_mut_or_clone(arc)
+    }
+
+    /// 把Arc变成指向数据的裸指针，引用计数不变。
+    /// 必须而且只能用from_raw还原一次，否则就会泄漏或者重复释放
+    pub fn into_raw(arc: Self) -> *const T {
+        let ptr = Arc::as_ptr(&arc);
+        std::mem::forget(arc);
+        ptr
+    }
+
+    /// # Safety
+    ///
+    /// ptr必须来自Arc::<T>::into_raw，而且每次into_raw只能对应一次from_raw
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // 从data字段退回到ArcData的开头
+        let offset = std::mem::offset_of!(ArcData<T>, data);
+        let data = ptr.byte_sub(offset) as *mut ArcData<T>;
+        Arc {
+            ptr: NonNull::new_unchecked(data),
+        }
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
     fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
+
+    /// n是加一之前的强引用计数。调用的时候新的Arc已经构造好了，panic的时候它会被drop，计数会被减回去
+    #[cfg(debug_assertions)]
+    fn check_cap(&self, n: usize) {
+        let max = self.data().max_strong;
+        assert!(n < max, "strong count exceeded the cap of {max}");
+    }
+
+    /// 和std一样，只有这一个Arc并且没有Weak的时候才返回&mut T。
+    /// 有Weak就拒绝：&mut T存活期间没有办法挡住它们upgrade
+    pub fn get_mut(arc: &mut Self) -> Option<&mut T> {
+        if !arc.is_unique() {
+            return None;
+        }
+        // Safety: Nothing else can access the data,
+        // since there's only one Arc, to which we have exclusive access,
+        // and there are no Weak pointers.
+        Some(unsafe { &mut **arc.data().data.get() })
+    }
+
+    /// 和get_mut不同，有Weak也可以成功：把强引用计数从1换成0，返回的guard存活期间
+    /// Weak::upgrade会当成数据已经没了返回None，guard drop的时候恢复成1，之后又可以upgrade。
+    /// 多出来的Arc可能只是别的线程upgrade之后马上就会drop的临时句柄，
+    /// 最多重试spins次等它们消失，还有别的Arc就返回None
+    pub fn get_mut_spinning(arc: &mut Self, spins: usize) -> Option<UpgradesLocked<'_, T>> {
+        for i in 0..=spins {
+            // Acquire matches the Release decrement in Arc::drop,
+            // so everything a temporarily upgraded Arc did to the data is visible.
+            if arc
+                .data()
+                .data_ref_count
+                .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Some(UpgradesLocked { arc });
+            }
+            if i < spins {
+                spin_loop();
+            }
+        }
+        None
+    }
+
+    /// 先锁住alloc_ref_count，这样检查强引用计数的时候别的Arc不能downgrade出新的Weak
+    fn is_unique(&mut self) -> bool {
+        // Acquire matches Weak::drop's Release decrement, to make sure any
+        // upgraded pointers are visible in the next data_ref_count.load.
+        if self
+            .data()
+            .alloc_ref_count
+            .compare_exchange(1, WEAK_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        // Acquire matches the Release decrement in Arc::drop,
+        // so everything the other Arcs did to the data is visible.
+        let unique = self.data().data_ref_count.load(Ordering::Acquire) == 1;
+        // Release matches the Acquire in downgrade, so any changes to the data
+        // made through the &mut T happen before the new Weak exists.
+        self.data().alloc_ref_count.store(1, Ordering::Release);
+        unique
+    }
+
+    fn into_ptr(self) -> NonNull<ArcData<T>> {
+        let ptr = self.ptr;
+        std::mem::forget(self);
+        ptr
+    }
+
+    /// 指向数据的裸指针，不影响引用计数，只在有Arc存活的时候有效
+    pub fn as_ptr(arc: &Self) -> *const T {
+        // UnsafeCell和ManuallyDrop都是repr(transparent)，data字段的地址就是T的地址。
+        // 不经过引用，保留整个分配的provenance，from_raw才能退回到ArcData的开头
+        unsafe { addr_of_mut!((*arc.ptr.as_ptr()).data) as *const T }
+    }
+
+    /// 强引用的个数，查引用环泄漏用。只是一个快照，别的线程随时可能改
+    pub fn strong_count(arc: &Self) -> usize {
+        arc.data().data_ref_count.load(Ordering::Acquire)
+    }
+
+    /// Weak的个数，不算所有Arc共同持有的那个。和strong_count一样只是快照
+    pub fn weak_count(arc: &Self) -> usize {
+        match arc.data().alloc_ref_count.load(Ordering::Acquire) {
+            // get_mut正在检查，只有它锁的住说明没有Weak
+            WEAK_LOCKED => 0,
+            n => n - 1,
+        }
+    }
+
+    /// 比较是不是同一个分配，不看内容
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        // 只比较地址，同一个分配的slice长度也一定相同
+        std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+
+    /// 对象池淘汰用：返回强引用最少的那个Arc的下标，一样多的时候取第一个。
+    /// 读到的计数只是一个快照，返回的时候可能已经变了
+    pub fn least_shared(arcs: &[Arc<T>]) -> Option<usize> {
+        arcs.iter()
+            .enumerate()
+            .min_by_key(|(_, a)| a.data().data_ref_count.load(Ordering::Relaxed))
+            .map(|(i, _)| i)
+    }
+
+    pub fn downgrade(arc: &Self) -> Weak<T> {
+        let mut n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
+        loop {
+            if n == WEAK_LOCKED {
+                // get_mut只锁很短的时间，等它放开
+                spin_loop();
+                n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
+                continue;
+            }
+            if n > usize::MAX / 2 {
+                std::process::abort();
+            }
+            // Acquire synchronises with get_mut's release-store.
+            if let Err(e) = arc.data().alloc_ref_count.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                n = e;
+                continue;
+            }
+            return Weak { ptr: arc.ptr };
+        }
+    }
+}
+
+impl<T> Arc<[T]> {
+    /// 按repr(C)的规则算ArcData<[T]>的布局：计数在前，元素按T的对齐接在后面。
+    /// Weak::drop里Box按Layout::for_value释放，算出来的必须和这里一样
+    fn slice_layout(len: usize) -> Layout {
+        let (layout, _) = Layout::new::<ArcData<()>>()
+            .extend(Layout::array::<T>(len).expect("slice too large"))
+            .expect("slice too large");
+        layout.pad_to_align()
+    }
+
+    /// items必须正好产生len个元素。
+    /// 中途panic的话这个分配和已经写进去的元素会泄漏，但不会读到未初始化的内存
+    fn from_iter_exact(len: usize, items: impl Iterator<Item = T>) -> Self {
+        let layout = Self::slice_layout(len);
+        // Safety: The layout is never zero-sized because of the counters.
+        let mem = unsafe { alloc(layout) };
+        if mem.is_null() {
+            handle_alloc_error(layout);
+        }
+        // 转换成胖指针，长度就是slice的元数据
+        let data = ptr::slice_from_raw_parts_mut(mem.cast::<T>(), len) as *mut ArcData<[T]>;
+        // Safety: The allocation is ours and large enough for ArcData<[T]> of this length.
+        unsafe {
+            addr_of_mut!((*data).data_ref_count).write(AtomicUsize::new(1));
+            addr_of_mut!((*data).alloc_ref_count).write(AtomicUsize::new(1));
+            #[cfg(debug_assertions)]
+            addr_of_mut!((*data).max_strong).write(usize::MAX);
+            let elems = addr_of_mut!((*data).data).cast::<T>();
+            let mut written = 0;
+            for item in items.take(len) {
+                elems.add(written).write(item);
+                written += 1;
+            }
+            assert_eq!(written, len, "iterator yielded too few items");
+            Arc {
+                ptr: NonNull::new_unchecked(data),
+            }
+        }
+    }
 }
 
-impl<T> Deref for Arc<T> {
+impl<T: Clone> Arc<[T]> {
+    /// 把items clone进一个新的Arc<[T]>，计数和元素在同一个分配里。
+    /// clone中途panic的话这个分配和已经clone出来的元素会泄漏，但不会读到未初始化的内存
+    pub fn from_slice(items: &[T]) -> Self {
+        Self::from_iter_exact(items.len(), items.iter().cloned())
+    }
+
+    /// 在末尾加一个元素。只有这一个Arc并且没有Weak的时候用realloc加长原来的分配，
+    /// 分配器后面还有空间的话就是原地加长，不用复制；
+    /// 否则把元素clone到一个新的更长的分配里，原来的分配和别的Arc看到的内容不变
+    pub fn push(arc: &mut Arc<[T]>, value: T) {
+        let len = arc.len();
+        if !arc.is_unique() {
+            *arc = Self::from_iter_exact(len + 1, arc.iter().cloned().chain(Some(value)));
+            return;
+        }
+        let old = Self::slice_layout(len);
+        let new = Self::slice_layout(len + 1);
+        // Safety: The allocation was made with the old layout, and nothing else
+        // can access it: there's only one Arc, to which we have exclusive access,
+        // and there are no Weak pointers.
+        let mem = unsafe { realloc(arc.ptr.as_ptr().cast::<u8>(), old, new.size()) };
+        if mem.is_null() {
+            handle_alloc_error(new);
+        }
+        let data = ptr::slice_from_raw_parts_mut(mem.cast::<T>(), len + 1) as *mut ArcData<[T]>;
+        // Safety: realloc kept the counters and the first len elements,
+        // and the new allocation has room for one more.
+        unsafe {
+            addr_of_mut!((*data).data).cast::<T>().add(len).write(value);
+            arc.ptr = NonNull::new_unchecked(data);
+        }
+    }
+}
+
+impl<T: Any + Send + Sync> Arc<T> {
+    /// 擦掉具体类型。std的Arc靠CoerceUnsized直接赋值就能转换，那个trait还不稳定，
+    /// 这里转换的是里面的NonNull，它自己的unsize转换是稳定的
+    pub fn into_any(arc: Self) -> Arc<dyn Any + Send + Sync> {
+        let arc = ManuallyDrop::new(arc);
+        let ptr: NonNull<ArcData<dyn Any + Send + Sync>> = arc.ptr;
+        Arc { ptr }
+    }
+}
+
+impl Arc<dyn Any + Send + Sync> {
+    /// 类型对得上就换回Arc<T>，计数不变，对不上原样还回来。
+    /// 只有Send + Sync的dyn Any能downcast，而且T也要求Send + Sync，结果还是可以跨线程用
+    pub fn downcast<T: Any + Send + Sync>(self) -> Result<Arc<T>, Self> {
+        if !(*self).is::<T>() {
+            return Err(self);
+        }
+        let arc = ManuallyDrop::new(self);
+        // 去掉vtable就是原来的ArcData<T>，布局和分配都没变
+        Ok(Arc {
+            ptr: arc.ptr.cast(),
+        })
+    }
+}
+
+/// get_mut_spinning返回的独占访问，存活期间Weak都upgrade不了
+#[must_use = "if unused the upgrades are unlocked immediately"]
+pub struct UpgradesLocked<'a, T: ?Sized> {
+    arc: &'a mut Arc<T>,
+}
+
+impl<T: ?Sized> Deref for UpgradesLocked<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: Same as DerefMut.
+        unsafe { &*self.arc.data().data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for UpgradesLocked<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: The data reference counter is zero, so no Weak can upgrade,
+        // and the only Arc is borrowed by this guard, so it can't be cloned,
+        // downgraded or dropped either. Nothing else can access the data.
+        unsafe { &mut *self.arc.data().data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for UpgradesLocked<'_, T> {
+    fn drop(&mut self) {
+        // Release matches the Acquire in Weak::upgrade,
+        // so upgraders see the changes made through this guard.
+        self.arc.data().data_ref_count.store(1, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized> Deref for Arc<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -58,8 +496,48 @@ impl<T> Deref for Arc<T> {
     }
 }
 
-impl<T> Weak<T> {
-    pub fn data(&self) -> &ArcData<T> {
+// 格式化和里面的T完全一样
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// 按值比较，和ptr_eq的按分配比较不是一回事；同一个分配直接算相等，不用再比内容
+impl<T: ?Sized + PartialEq> PartialEq for Arc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(self, other) || **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for Arc<T> {}
+
+impl<T: ?Sized + Hash> Hash for Arc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T> From<T> for Arc<T> {
+    fn from(data: T) -> Self {
+        Arc::new(data)
+    }
+}
+
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Weak<T> {
+    fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
 
@@ -69,22 +547,25 @@ impl<T> Weak<T> {
             if n == 0 {
                 return None;
             }
-            assert!(n < usize::MAX);
+            assert!(n < usize::MAX / 2);
             if let Err(e) = self.data().data_ref_count.compare_exchange_weak(
                 n,
                 n + 1,
-                Ordering::Relaxed,
+                Ordering::Acquire,
                 Ordering::Relaxed,
             ) {
                 n = e;
                 continue;
             }
-            return Some(Arc { ptr: self.ptr });
+            let arc = Arc { ptr: self.ptr };
+            #[cfg(debug_assertions)]
+            arc.check_cap(n);
+            return Some(arc);
         }
     }
 }
 
-impl<T> Clone for Weak<T> {
+impl<T: ?Sized> Clone for Weak<T> {
     fn clone(&self) -> Self {
         if self.data().alloc_ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2 {
             std::process::abort();
@@ -93,27 +574,39 @@ impl<T> Clone for Weak<T> {
     }
 }
 
-impl<T> Drop for Weak<T> {
+impl<T: ?Sized> Drop for Weak<T> {
     fn drop(&mut self) {
-        if self.data().alloc_ref_count.fetch_sub(1, Ordering::Relaxed) == 1 {
+        // Release matches the Acquire in is_unique.
+        if self.data().alloc_ref_count.fetch_sub(1, Ordering::Release) == 1 {
             fence(Ordering::Acquire);
             drop(unsafe { Box::from_raw(self.ptr.as_ptr()) })
         }
     }
 }
 
-impl<T> Clone for Arc<T> {
+impl<T: ?Sized> Clone for Arc<T> {
     fn clone(&self) -> Self {
-        if self.data().data_ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2 {
+        let n = self.data().data_ref_count.fetch_add(1, Ordering::Relaxed);
+        if n > usize::MAX / 2 {
             std::process::abort();
         }
-        Arc { ptr: self.ptr }
+        let arc = Arc { ptr: self.ptr };
+        #[cfg(debug_assertions)]
+        arc.check_cap(n);
+        arc
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized> Drop for Arc<T> {
     fn drop(&mut self) {
-        if self.data().data_ref_count.fetch_sub(1, Ordering::Release) == 1 {
+        self.release(1);
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
+    /// 一次性放掉n个指向同一个分配的强引用
+    fn release(&self, n: usize) {
+        if self.data().data_ref_count.fetch_sub(n, Ordering::Release) == n {
             fence(Ordering::Acquire);
             // Safety: The data reference counter is zero,
             // so nothing will access the data anymore.
@@ -125,4 +618,482 @@ impl<T> Drop for Arc<T> {
             drop(Weak { ptr: self.ptr });
         }
     }
+
+    /// drop一大批Arc，同一个分配只做一次fetch_sub，
+    /// 比如一万个同一个Arc的clone只需要一次原子操作而不是一万次。
+    /// 要先按指针排序，所以都是不同的Arc的时候比逐个drop慢，见examples/drop_all.rs
+    pub fn drop_all(arcs: Vec<Arc<T>>) {
+        let mut ptrs: Vec<_> = arcs.into_iter().map(Arc::into_ptr).collect();
+        ptrs.sort_unstable();
+        for group in ptrs.chunk_by(|a, b| std::ptr::addr_eq(a.as_ptr(), b.as_ptr())) {
+            // 借用group里第一个指针来放掉整组的引用，它自己不能再被drop
+            let arc = ManuallyDrop::new(Arc { ptr: group[0] });
+            arc.release(group.len());
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+    use std::{sync::atomic::AtomicBool, thread};
+
+    #[test]
+    fn get_mut_with_weak() {
+        let mut a = Arc::new(1);
+        let w = Arc::downgrade(&a);
+        // 有Weak就拒绝，同一个线程里也可以照常upgrade
+        assert!(Arc::get_mut(&mut a).is_none());
+        assert_eq!(*w.upgrade().unwrap(), 1);
+        drop(w);
+        *Arc::get_mut(&mut a).unwrap() = 2;
+        assert_eq!(*a, 2);
+        let w = Arc::downgrade(&a);
+        assert_eq!(Arc::weak_count(&a), 1);
+        drop(w);
+        assert_eq!(Arc::weak_count(&a), 0);
+    }
+
+    #[test]
+    fn get_mut_spinning_locks_out_upgrades() {
+        let mut a = Arc::new(0);
+        let w = Arc::downgrade(&a);
+        // 有Weak的时候get_mut拒绝，get_mut_spinning可以拿到
+        assert!(Arc::get_mut(&mut a).is_none());
+        let mut v = Arc::get_mut_spinning(&mut a, 0).unwrap();
+        *v = 1;
+        assert!(w.upgrade().is_none());
+        drop(v);
+        assert_eq!(*w.upgrade().unwrap(), 1);
+
+        // 别的强引用一直存在的时候，等完了还是拒绝
+        let b = a.clone();
+        assert!(Arc::get_mut_spinning(&mut a, 100).is_none());
+        drop(b);
+        assert!(Arc::get_mut_spinning(&mut a, 100).is_some());
+        assert_eq!(Arc::strong_count(&a), 1);
+    }
+
+    #[test]
+    fn try_unwrap_works() {
+        let a = Arc::new(String::from("only"));
+        assert_eq!(Arc::try_unwrap(a).ok().as_deref(), Some("only"));
+
+        let a = Arc::new(String::from("shared"));
+        let b = a.clone();
+        let Err(a) = Arc::try_unwrap(a) else {
+            panic!("unwrapped a shared Arc");
+        };
+        assert_eq!(*a, "shared");
+        drop(b);
+
+        // 有Weak也能拿出来，之后Weak就upgrade不了了
+        let w = Arc::downgrade(&a);
+        assert_eq!(Arc::try_unwrap(a).ok().as_deref(), Some("shared"));
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn into_inner_exactly_one_wins() {
+        for _ in 0..100 {
+            let a = Arc::new(String::from("last"));
+            let b = a.clone();
+            let (x, y) = thread::scope(|s| {
+                let x = s.spawn(|| Arc::into_inner(a));
+                let y = s.spawn(|| Arc::into_inner(b));
+                (x.join().unwrap(), y.join().unwrap())
+            });
+            match (x, y) {
+                (Some(v), None) | (None, Some(v)) => assert_eq!(v, "last"),
+                other => panic!("expected exactly one Some, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn get_mut_denied_when_shared() {
+        let mut a = Arc::new(1);
+        let b = a.clone();
+        assert!(Arc::get_mut(&mut a).is_none());
+        drop(b);
+        *Arc::get_mut(&mut a).unwrap() += 1;
+        assert_eq!(*a, 2);
+    }
+
+    #[derive(PartialEq, Debug)]
+    struct CountClones(i32);
+    static NUM_CLONES: AtomicUsize = AtomicUsize::new(0);
+    impl Clone for CountClones {
+        fn clone(&self) -> Self {
+            NUM_CLONES.fetch_add(1, Ordering::Relaxed);
+            CountClones(self.0)
+        }
+    }
+
+    #[test]
+    fn get_mut_or_clone_works() {
+        // 唯一的Arc，原地修改
+        let mut a = Arc::new(CountClones(1));
+        let p = a.ptr;
+        Arc::get_mut_or_clone(&mut a).0 += 1;
+        assert_eq!(a.ptr, p);
+        assert_eq!(NUM_CLONES.load(Ordering::Relaxed), 0);
+
+        // 有别的Arc，clone一份
+        let b = a.clone();
+        Arc::get_mut_or_clone(&mut a).0 += 1;
+        assert_eq!(NUM_CLONES.load(Ordering::Relaxed), 1);
+        assert_eq!(*a, CountClones(3));
+        assert_eq!(*b, CountClones(2));
+
+        // 只剩Weak，move到新的分配，Weak失效
+        drop(b);
+        let w = Arc::downgrade(&a);
+        Arc::get_mut_or_clone(&mut a).0 += 1;
+        assert_eq!(NUM_CLONES.load(Ordering::Relaxed), 1);
+        assert!(w.upgrade().is_none());
+        assert_eq!(*a, CountClones(4));
+        assert_eq!(a.data().alloc_ref_count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn make_mut_copies_on_write() {
+        let mut a = Arc::new(vec![1]);
+        let b = a.clone();
+        Arc::make_mut(&mut a).push(2);
+        assert_eq!(*a, [1, 2]);
+        assert_eq!(*b, [1]);
+
+        // 唯一的Arc原地修改，不会clone
+        let before: *const Vec<i32> = &*a;
+        Arc::make_mut(&mut a).push(3);
+        assert_eq!(&*a as *const Vec<i32>, before);
+        assert_eq!(*a, [1, 2, 3]);
+    }
+
+    #[test]
+    fn downcast_keeps_send_sync() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct DetectDrop(i32);
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let any = Arc::into_any(Arc::new(DetectDrop(7)));
+        let other = any.clone();
+        let any = any.downcast::<String>().unwrap_err();
+        let x = any.downcast::<DetectDrop>().ok().unwrap();
+        assert_send_sync(&x);
+        assert_eq!(x.0, 7);
+        std::thread::spawn(move || assert_eq!(x.0, 7))
+            .join()
+            .unwrap();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        // 通过dyn Any drop也要调用到T的drop
+        drop(other);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn from_slice_works() {
+        let a = Arc::from_slice(&[1, 2, 3]);
+        let b = a.clone();
+        assert_eq!(&*b, &[1, 2, 3]);
+        assert_eq!(b.len(), 3);
+        let w = Arc::downgrade(&a);
+        drop(a);
+        assert_eq!(w.upgrade().as_deref(), Some(&[1, 2, 3][..]));
+        drop(b);
+        assert!(w.upgrade().is_none());
+
+        let empty: Arc<[String]> = Arc::from_slice(&[]);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn push_grows_unique_slice() {
+        let mut a: Arc<[i32]> = Arc::from_slice(&[]);
+        for i in 0..10 {
+            Arc::push(&mut a, i);
+        }
+        assert_eq!(&*a, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        // 加长之后计数还在，Weak和clone照常工作
+        let w = Arc::downgrade(&a);
+        assert_eq!(Arc::strong_count(&a), 1);
+        assert_eq!(w.upgrade().as_deref(), Some(&*a));
+    }
+
+    #[test]
+    fn push_reallocates_shared_slice() {
+        let mut a = Arc::from_slice(&[String::from("a"), String::from("b")]);
+        let b = a.clone();
+        Arc::push(&mut a, String::from("c"));
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert_eq!(&*a, ["a", "b", "c"]);
+        assert_eq!(&*b, ["a", "b"]);
+        assert_eq!(Arc::strong_count(&b), 1);
+
+        // 只有Weak的时候也不能原地改，Weak还是指向原来的内容
+        let w = Arc::downgrade(&b);
+        let mut b = b;
+        Arc::push(&mut b, String::from("d"));
+        assert_eq!(&*b, ["a", "b", "d"]);
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn from_slice_drops_each_element_once() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+        #[derive(Clone)]
+        struct DetectDrop;
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let items = [DetectDrop, DetectDrop, DetectDrop];
+        let a = Arc::from_slice(&items);
+        drop(items);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 3);
+        let b = a.clone();
+        drop(a);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 3);
+        drop(b);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn drop_all_works() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct DetectDrop;
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let a = Arc::new(DetectDrop);
+        let b = Arc::new(DetectDrop);
+        let mut arcs: Vec<_> = (0..10_000).map(|_| a.clone()).collect();
+        arcs.push(b.clone());
+        arcs.push(b);
+        Arc::drop_all(arcs);
+        // b的两个Arc都在里面，被drop了一次；a外面还有一个
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+        assert_eq!(a.data().data_ref_count.load(Ordering::Relaxed), 1);
+        Arc::drop_all(vec![a]);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn new_with_weak_works() {
+        static LAST_OWNER: AtomicBool = AtomicBool::new(false);
+        struct Node {
+            me: Weak<Node>,
+            value: i32,
+        }
+        impl Drop for Node {
+            fn drop(&mut self) {
+                // 自己被drop的时候已经没有Arc了，upgrade一定失败
+                LAST_OWNER.store(self.me.upgrade().is_none(), Ordering::Relaxed);
+            }
+        }
+        let a = Arc::new_with_weak(|me| {
+            assert!(me.upgrade().is_none());
+            Node { me, value: 1 }
+        });
+        assert_eq!(a.me.upgrade().unwrap().value, 1);
+        let b = a.clone();
+        drop(a);
+        assert!(!LAST_OWNER.load(Ordering::Relaxed));
+        drop(b);
+        assert!(LAST_OWNER.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn counts_work() {
+        let a = Arc::new(());
+        let b = a.clone();
+        let c = a.clone();
+        assert_eq!(Arc::strong_count(&a), 3);
+        drop(c);
+        assert_eq!(Arc::strong_count(&a), 2);
+        assert_eq!(Arc::weak_count(&a), 0);
+        let w = Arc::downgrade(&b);
+        assert_eq!(Arc::weak_count(&a), 1);
+        drop(w);
+        assert_eq!(Arc::weak_count(&a), 0);
+    }
+
+    #[test]
+    fn from_and_default_work() {
+        let a: Arc<Vec<u8>> = Arc::default();
+        assert!(a.is_empty());
+        let b = Arc::from(Vec::<u8>::new());
+        assert!(b.is_empty());
+        let c: Arc<Vec<u8>> = vec![1, 2].into();
+        assert_eq!(Arc::try_unwrap(c).ok(), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn fmt_forwards_to_inner() {
+        let s = String::from("hi");
+        let a = Arc::new(s.clone());
+        assert_eq!(format!("{a}"), format!("{s}"));
+        assert_eq!(format!("{a:?}"), format!("{s:?}"));
+        let n = Arc::new(42);
+        assert_eq!(format!("{n:>5}"), format!("{:>5}", 42));
+        assert_eq!(format!("{n:?}"), "42");
+        assert_eq!(format!("{:?}", Arc::from_slice(&[1, 2])), "[1, 2]");
+    }
+
+    #[test]
+    fn eq_and_hash_are_value_based() {
+        use std::collections::HashSet;
+        let a = Arc::new(String::from("key"));
+        let b = Arc::new(String::from("key"));
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert_eq!(a, b);
+        assert_ne!(a, Arc::new(String::from("other")));
+        let set: HashSet<_> = [a.clone(), b, a].into_iter().collect();
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct DetectDrop(i32);
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let a = Arc::new(DetectDrop(7));
+        let b = a.clone();
+        assert_eq!(Arc::as_ptr(&a), &*b as *const DetectDrop);
+        let raw = Arc::into_raw(a);
+        assert_eq!(unsafe { (*raw).0 }, 7);
+        assert_eq!(Arc::strong_count(&b), 2);
+        let a = unsafe { Arc::from_raw(raw) };
+        assert_eq!(Arc::strong_count(&a), 2);
+        assert!(Arc::ptr_eq(&a, &b));
+        drop(a);
+        drop(b);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn ptr_eq_works() {
+        let a = Arc::new(5);
+        let b = a.clone();
+        let c = Arc::new(5);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &c));
+        assert_eq!(*a, *c);
+    }
+
+    #[test]
+    fn least_shared_works() {
+        let arcs = [Arc::new(1), Arc::new(2), Arc::new(3)];
+        let _a = [arcs[0].clone(), arcs[0].clone()];
+        let _c = arcs[2].clone();
+        assert_eq!(Arc::least_shared(&arcs), Some(1));
+        assert_eq!(Arc::<i32>::least_shared(&[]), None);
+    }
+
+    #[test]
+    fn new_capped_within_cap() {
+        // 最多同时三个Arc
+        let a = Arc::new_capped(1, 3);
+        let b = a.clone();
+        let c = b.clone();
+        drop(b);
+        let _d = c.clone();
+        assert_eq!(Arc::strong_count(&a), 3);
+        let w = Arc::downgrade(&a);
+        drop(c);
+        let _e = w.upgrade().unwrap();
+        assert_eq!(*a, 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "exceeded the cap")]
+    fn new_capped_panics_past_cap() {
+        // 两个Arc可以，第三个panic
+        let a = Arc::new_capped(1, 2);
+        let _b = a.clone();
+        let _c = a.clone();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "exceeded the cap")]
+    fn new_capped_upgrade_past_cap() {
+        let a = Arc::new_capped(1, 1);
+        let _b = Arc::downgrade(&a).upgrade();
+    }
+
+    #[test]
+    fn replace_works() {
+        let mut a = Arc::new(String::from("old"));
+        let w = Arc::downgrade(&a);
+        // 有Weak的时候拒绝
+        assert_eq!(Arc::replace(&mut a, String::from("new")), None);
+        drop(w);
+        let old = Arc::replace(&mut a, String::from("new"));
+        assert_eq!(old.as_deref(), Some("old"));
+        let b = a.clone();
+        assert_eq!(Arc::replace(&mut a, String::from("newer")), None);
+        assert_eq!(*b, "new");
+    }
+}
+
+// RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+#[cfg(all(test, loom))]
+mod loom_test {
+    use super::*;
+
+    #[test]
+    fn get_mut_races_upgrade() {
+        loom::model(|| {
+            let mut a = Arc::new(1);
+            let w = Arc::downgrade(&a);
+            let t = loom::thread::spawn(move || {
+                // get_mut成功的话Weak已经drop了，upgrade出来的值一定没被改过
+                if let Some(b) = w.upgrade() {
+                    assert_eq!(*b, 1);
+                }
+            });
+            if let Some(v) = Arc::get_mut(&mut a) {
+                *v = 2;
+            }
+            t.join().unwrap();
+        });
+    }
+
+    // 用loom的UnsafeCell，两边对数据的访问没有先后关系的话loom会报错
+    struct Cell(loom::cell::UnsafeCell<i32>);
+    unsafe impl Sync for Cell {}
+
+    #[test]
+    fn get_mut_spinning_races_upgrade() {
+        loom::model(|| {
+            let mut a = Arc::new(Cell(loom::cell::UnsafeCell::new(1)));
+            let w = Arc::downgrade(&a);
+            let t = loom::thread::spawn(move || {
+                // upgrade要么在get_mut_spinning之前成功，要么在guard drop之后看到2，
+                // guard存活期间只能是None
+                if let Some(b) = w.upgrade() {
+                    let v = b.0.with(|p| unsafe { *p });
+                    assert!(v == 1 || v == 2);
+                }
+            });
+            if let Some(v) = Arc::get_mut_spinning(&mut a, 2) {
+                v.0.with_mut(|p| unsafe { *p = 2 });
+            }
+            t.join().unwrap();
+        });
+    }
 }
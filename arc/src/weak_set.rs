@@ -0,0 +1,56 @@
+//! 记录一组共享对象但不让它们一直活着，死掉的Weak在遍历时跳过，prune的时候才真正删掉
+use crate::third::{Arc, Weak};
+
+pub struct WeakSet<T> {
+    items: Vec<Weak<T>>,
+}
+
+impl<T> WeakSet<T> {
+    pub fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+
+    /// 不检查重复，同一个Arc插入两次会被遍历到两次
+    pub fn insert(&mut self, arc: &Arc<T>) {
+        self.items.push(Arc::downgrade(arc));
+    }
+
+    /// 依次upgrade，返回的Arc会让对象在使用期间一直活着
+    pub fn iter_live(&self) -> impl Iterator<Item = Arc<T>> + '_ {
+        self.items.iter().filter_map(Weak::upgrade)
+    }
+
+    pub fn prune(&mut self) {
+        self.items.retain(|weak| weak.upgrade().is_some());
+    }
+}
+
+impl<T> Default for WeakSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dead_entries_are_skipped_and_pruned() {
+        let a = Arc::new(1);
+        let b = Arc::new(2);
+        let c = Arc::new(3);
+        let mut set = WeakSet::new();
+        for arc in [&a, &b, &c] {
+            set.insert(arc);
+        }
+        drop(b);
+        let live: Vec<i32> = set.iter_live().map(|arc| *arc).collect();
+        assert_eq!(live, [1, 3]);
+        assert_eq!(set.items.len(), 3);
+        set.prune();
+        assert_eq!(set.items.len(), 2);
+        drop((a, c));
+        assert_eq!(set.iter_live().count(), 0);
+    }
+}
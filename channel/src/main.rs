@@ -1,29 +1,277 @@
 use std::{
     cell::UnsafeCell,
-    collections::VecDeque,
-    mem::MaybeUninit,
+    cmp::Ordering as CmpOrdering,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    hash::Hash,
+    io::{self, Read, Write},
+    mem::{self, MaybeUninit},
+    ops::Deref,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
         Arc, Condvar, Mutex,
-    }, thread::{Thread, self}, marker::PhantomData,
+    }, thread::{Thread, self, JoinHandle}, marker::PhantomData,
+    task::{Poll, Waker},
+    time::{Duration, Instant},
 };
 
 pub struct Channel<T> {
-    queue: Mutex<VecDeque<T>>,
+    queue: QueueMutex<T>,
     item_ready: Condvar,
+    // Some(cap)表示队列满了以后丢掉最老的消息，而不是无限增长
+    drop_oldest: Option<usize>,
+    high_water: AtomicUsize,
+    notify: NotifyStrategy,
+    // select2用的共享通知，每次send之后都会通知它
+    notifier: Option<Arc<Notifier>>,
+    // 带超时的接收用它计时，None就是真实时间
+    clock: Option<Arc<dyn Clock>>,
+}
+
+#[cfg(not(test))]
+type QueueMutex<T> = Mutex<VecDeque<T>>;
+#[cfg(test)]
+type QueueMutex<T> = CountingMutex<VecDeque<T>>;
+
+/// 测试里用它代替Channel队列的Mutex，数一数加了几次锁。
+/// 只拦下lock，其他的方法通过Deref交给里面的Mutex
+#[cfg(test)]
+struct CountingMutex<T> {
+    inner: Mutex<T>,
+    locks: AtomicUsize,
+}
+
+#[cfg(test)]
+impl<T> CountingMutex<T> {
+    fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            locks: AtomicUsize::new(0),
+        }
+    }
+
+    fn lock(&self) -> std::sync::LockResult<std::sync::MutexGuard<'_, T>> {
+        self.locks.fetch_add(1, Ordering::Relaxed);
+        self.inner.lock()
+    }
+
+    fn lock_count(&self) -> usize {
+        self.locks.load(Ordering::Relaxed)
+    }
+
+    fn into_inner(self) -> std::sync::LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+#[cfg(test)]
+impl<T> Deref for CountingMutex<T> {
+    type Target = Mutex<T>;
+
+    fn deref(&self) -> &Mutex<T> {
+        &self.inner
+    }
+}
+
+/// 带超时的接收都通过它读时间，测试里换成ManualClock就可以不用真的sleep
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// 还剩remaining的时候这一次最多在Condvar上睡多久，醒来之后会重新读now。
+    /// 真实时间直接睡到底，手动的时钟是跳着走的，只能隔一小段真实时间看一次
+    fn wait_slice(&self, remaining: Duration) -> Duration {
+        remaining
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// 只有调用advance的时候时间才会往前走
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        *self.now.lock().unwrap() += d;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn wait_slice(&self, remaining: Duration) -> Duration {
+        remaining.min(Duration::from_millis(1))
+    }
+}
+
+// 没有注入Clock的时候用真实时间
+fn clock_now(clock: Option<&dyn Clock>) -> Instant {
+    clock.map_or_else(Instant::now, |c| c.now())
+}
+
+fn clock_wait_slice(clock: Option<&dyn Clock>, remaining: Duration) -> Duration {
+    clock.map_or(remaining, |c| c.wait_slice(remaining))
+}
+
+/// send之后怎么唤醒等待的receiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotifyStrategy {
+    /// 只唤醒一个，一条消息只需要一个消费者，没有惊群。
+    /// 前提是被唤醒的消费者一定会把消息取走；如果它醒来发现条件不满足又睡回去了(比如recv_array)，
+    /// 这次唤醒就浪费了，别的能处理这条消息的消费者也不会被叫醒
+    #[default]
+    NotifyOne,
+    /// 唤醒所有等待的消费者，各自重新检查队列，不会丢失唤醒，代价是每次send都有惊群
+    NotifyAll,
 }
 
 impl<T> Channel<T> {
     pub fn new() -> Self {
         Self {
-            queue: Mutex::new(VecDeque::new()),
+            queue: QueueMutex::new(VecDeque::new()),
             item_ready: Condvar::new(),
+            drop_oldest: None,
+            high_water: AtomicUsize::new(0),
+            notify: NotifyStrategy::NotifyOne,
+            notifier: None,
+            clock: None,
+        }
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock: Some(clock),
+            ..Self::new()
+        }
+    }
+
+    fn now(&self) -> Instant {
+        clock_now(self.clock.as_deref())
+    }
+
+    /// 按channel的时钟最多等timeout，期间有消息就取出来。
+    /// 醒来(包括虚假唤醒)之后重新检查队列，并且只等剩下的时间
+    fn pop_within(&self, timeout: Duration) -> Option<T> {
+        let deadline = self.now() + timeout;
+        let mut b = self.queue.lock().unwrap();
+        loop {
+            if let Some(message) = b.pop_front() {
+                return Some(message);
+            }
+            let now = self.now();
+            if now >= deadline {
+                return None;
+            }
+            let slice = clock_wait_slice(self.clock.as_deref(), deadline - now);
+            b = self.item_ready.wait_timeout(b, slice).unwrap().0;
+        }
+    }
+
+    /// 要用select2同时等待的几个channel需要用同一个Notifier构造
+    pub fn with_notifier(notifier: Arc<Notifier>) -> Self {
+        Self {
+            notifier: Some(notifier),
+            ..Self::new()
+        }
+    }
+
+    /// 同一个channel上混用不同的等待条件(比如receive和recv_array)时用NotifyAll
+    pub fn new_with_notify(notify: NotifyStrategy) -> Self {
+        Self {
+            notify,
+            ..Self::new()
+        }
+    }
+
+    /// 最多保留cap条最新的消息，满了之后send会把最老的一条挤掉，send永远不会阻塞
+    pub fn new_drop_oldest(cap: usize) -> Self {
+        assert!(cap > 0, "capacity must be non-zero");
+        Self {
+            drop_oldest: Some(cap),
+            ..Self::new()
         }
     }
 
+    /// push_back是在锁里做的，同一个生产者的send按程序顺序先后拿锁，
+    /// 所以每个生产者自己的消息一定按发送顺序到达(不同生产者之间按拿到锁的先后)
     pub fn send(&self, message: T) {
-        self.queue.lock().unwrap().push_back(message);
-        self.item_ready.notify_one();
+        let mut b = self.queue.lock().unwrap();
+        let evicted = self.push_locked(&mut b, message);
+        drop(b);
+        self.notify_sent();
+        // 被挤掉的消息在锁外面drop
+        drop(evicted);
+    }
+
+    /// 一次加锁放进所有消息，最后notify_all一次，批量生产的时候少抢很多次锁。
+    /// 迭代器是在锁里被消费的，不要在里面做耗时的事情
+    pub fn send_all(&self, items: impl IntoIterator<Item = T>) {
+        let mut b = self.queue.lock().unwrap();
+        let evicted: Vec<T> = items
+            .into_iter()
+            .filter_map(|m| self.push_locked(&mut b, m))
+            .collect();
+        drop(b);
+        self.item_ready.notify_all();
+        if let Some(n) = &self.notifier {
+            n.notify();
+        }
+        drop(evicted);
+    }
+
+    /// 和send一样，但是在队列的锁里检查closed，已经关闭了就把消息还回去。
+    /// close也要在队列的锁里置位，这样关闭之后不会再有消息进队列
+    fn send_unless_closed(&self, message: T, closed: &AtomicBool) -> Result<(), T> {
+        let mut b = self.queue.lock().unwrap();
+        if closed.load(Ordering::Relaxed) {
+            return Err(message);
+        }
+        let evicted = self.push_locked(&mut b, message);
+        drop(b);
+        self.notify_sent();
+        drop(evicted);
+        Ok(())
+    }
+
+    fn notify_sent(&self) {
+        match self.notify {
+            NotifyStrategy::NotifyOne => self.item_ready.notify_one(),
+            NotifyStrategy::NotifyAll => self.item_ready.notify_all(),
+        }
+        if let Some(n) = &self.notifier {
+            n.notify();
+        }
+    }
+
+    /// 调用方已经拿着queue的锁。返回被挤掉的消息，由调用方在锁外drop
+    fn push_locked(&self, queue: &mut VecDeque<T>, message: T) -> Option<T> {
+        let evicted = match self.drop_oldest {
+            Some(cap) if queue.len() >= cap => queue.pop_front(),
+            _ => None,
+        };
+        queue.push_back(message);
+        self.high_water.fetch_max(queue.len(), Ordering::Relaxed);
+        evicted
     }
 
     pub fn receive(&self) -> T {
@@ -36,12 +284,156 @@ impl<T> Channel<T> {
             }
         }
     }
+
+    /// 不阻塞，队列里没有消息就直接返回None
+    pub fn try_receive(&self) -> Option<T> {
+        self.queue.lock().unwrap().pop_front()
+    }
+
+    /// 最多等timeout，虚假唤醒之后会重新检查队列，并且只等剩下的时间。时间按channel的Clock算
+    pub fn receive_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.pop_within(timeout).ok_or(RecvTimeoutError::Timeout)
+    }
+
+    /// 和receive一样，额外返回这次调用阻塞了多久(从进入到拿到消息)，用来观察消费者是不是在饿着
+    pub fn recv_timed(&self) -> (T, Duration) {
+        let start = self.now();
+        let message = self.receive();
+        (message, self.now() - start)
+    }
+
+    /// 和receive一样，额外返回取出这条消息时后面还排着多少条，是在同一次加锁里算的，
+    /// 可以用来判断消费者是不是跟不上了
+    pub fn recv_with_lag(&self) -> (T, usize) {
+        let b = self.queue.lock().unwrap();
+        let mut b = self.item_ready.wait_while(b, |q| q.is_empty()).unwrap();
+        let message = b.pop_front().unwrap();
+        (message, b.len())
+    }
+
+    /// 阻塞等消息，等待期间每隔tick调用一次on_tick做些定期的杂事，on_tick是在锁外调用的。
+    /// 消息到了就立刻返回，不会再等这一轮tick
+    /// tick按channel的Clock算
+    pub fn recv_with_tick(&self, tick: Duration, mut on_tick: impl FnMut()) -> T {
+        let mut next_tick = self.now() + tick;
+        let mut b = self.queue.lock().unwrap();
+        loop {
+            if let Some(message) = b.pop_front() {
+                return message;
+            }
+            let now = self.now();
+            if now >= next_tick {
+                drop(b);
+                on_tick();
+                next_tick = self.now() + tick;
+                b = self.queue.lock().unwrap();
+                continue;
+            }
+            let slice = clock_wait_slice(self.clock.as_deref(), next_tick - now);
+            b = self.item_ready.wait_timeout(b, slice).unwrap().0;
+        }
+    }
+
+    /// 和receive一样阻塞，但是token被cancel之后会被唤醒并返回Cancelled。
+    /// 已经有消息的时候优先返回消息
+    pub fn recv_cancellable(&self, token: &CancellationToken) -> Result<T, Cancelled>
+    where
+        T: Send,
+    {
+        // 先注册再拿队列的锁，cancel的顺序是 置位 -> waiters锁 -> 队列锁，不会丢失唤醒
+        let _registration = token.register(self);
+        let b = self.queue.lock().unwrap();
+        let mut b = self
+            .item_ready
+            .wait_while(b, |q| q.is_empty() && !token.is_cancelled())
+            .unwrap();
+        b.pop_front().ok_or(Cancelled)
+    }
+
+    /// 不阻塞，一次加锁取走当前排队的所有消息，按FIFO顺序返回
+    pub fn drain(&self) -> Vec<T> {
+        let queue = mem::take(&mut *self.queue.lock().unwrap());
+        queue.into()
+    }
+
+    /// 关闭的时候用：拿走整个channel，把剩下的每条消息按顺序交给f处理一次，保证没有消息被悄悄drop掉
+    pub fn drain_each(self, f: impl FnMut(T)) {
+        self.queue.into_inner().unwrap().into_iter().for_each(f);
+    }
+
+    /// 在锁里看一眼队首的消息，不取走。用闭包是因为借用不能活过锁
+    pub fn peek<R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.queue.lock().unwrap().front().map(f)
+    }
+
+    /// 不取走任何消息，clone一份排在第n个(从0开始)的消息，超出范围返回None。
+    /// 和len一样只是快照，之后receive拿到的未必是它
+    pub fn peek_nth(&self, n: usize) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.queue.lock().unwrap().get(n).cloned()
+    }
+
+    /// 当前排队的消息数，只是一个快照，返回的时候可能已经变了
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// 和len一样只是一个快照
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().is_empty()
+    }
+
+    /// 队列曾经达到过的最大长度，队列清空以后也不会回落，用来估计容量该设多大
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    /// 阻塞到队列里至少有N条消息，然后在同一次加锁里按顺序取出N条。
+    /// 注意默认send只会notify_one，要和别的receive在同一个channel上混用的话需要NotifyAll，否则可能吞掉唤醒
+    pub fn recv_array<const N: usize>(&self) -> [T; N] {
+        let b = self.queue.lock().unwrap();
+        let mut b = self.item_ready.wait_while(b, |q| q.len() < N).unwrap();
+        std::array::from_fn(|_| b.pop_front().unwrap())
+    }
+}
+
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub struct OneShotChannelWithPanic<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     in_use: AtomicBool,
-    ready: AtomicBool,
+    // EMPTY/READY/CONSUMED，一个原子变量就不会读到ready和consumed对不上的中间状态
+    state: AtomicU8,
+}
+
+/// one-shot的receive失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OneShotError {
+    /// 还没有send
+    NotReady,
+    /// 消息已经被取走了
+    AlreadyConsumed,
+}
+
+// one-shot里消息的状态
+const EMPTY: u8 = 0;
+const READY: u8 = 1;
+const CONSUMED: u8 = 2;
+
+/// READY换成CONSUMED，成功了就只有调用方可以读消息
+fn take_message(state: &AtomicU8) -> Result<(), OneShotError> {
+    // Acquire matches the Release store of READY in send.
+    match state.compare_exchange(READY, CONSUMED, Ordering::Acquire, Ordering::Relaxed) {
+        Ok(_) => Ok(()),
+        Err(CONSUMED) => Err(OneShotError::AlreadyConsumed),
+        Err(_) => Err(OneShotError::NotReady),
+    }
 }
 
 impl<T> OneShotChannelWithPanic<T> {
@@ -49,7 +441,7 @@ impl<T> OneShotChannelWithPanic<T> {
         Self {
             message: UnsafeCell::new(MaybeUninit::uninit()),
             in_use: AtomicBool::new(false),
-            ready: AtomicBool::new(false),
+            state: AtomicU8::new(EMPTY),
         }
     }
 
@@ -60,27 +452,56 @@ impl<T> OneShotChannelWithPanic<T> {
         unsafe {
             (*self.message.get()).write(message);
         }
-        self.ready.store(true, Ordering::Release)
+        self.state.store(READY, Ordering::Release)
     }
 
     pub fn is_ready(&self) -> bool {
-        self.ready.load(Ordering::Acquire)
+        self.state.load(Ordering::Acquire) == READY
+    }
+
+    /// 不会panic，没有消息的时候返回原因
+    pub fn receive(&self) -> Result<T, OneShotError> {
+        take_message(&self.state)?;
+        // Safety: The state was READY, so the message is initialized,
+        // and moving it to CONSUMED means only we read it.
+        Ok(unsafe { (*self.message.get()).assume_init_read() })
     }
 
     /// Panics if no message is available yet,
     /// or if the message was already consumed.
     /// Tip: Use `is_ready` to check first.
-    pub fn receive(&self) -> T {
-        if !self.ready.swap(false, Ordering::Acquire) {
-            panic!("no message available!");
+    pub fn receive_unchecked(&self) -> T {
+        match self.receive() {
+            Ok(message) => message,
+            Err(e) => panic!("no message available: {e:?}"),
         }
-        unsafe { (*self.message.get()).assume_init_read() }
+    }
+
+    /// 不关心失败原因的receive：还没发送或者已经被取走了都返回None
+    pub fn try_receive(&self) -> Option<T> {
+        self.receive().ok()
+    }
+
+    /// 消息被取走之后可以重新用来send一次。&mut self保证这时候没有别人在用它
+    pub fn reset(&mut self) {
+        assert!(
+            *self.state.get_mut() != READY,
+            "can't reset while a message is still pending"
+        );
+        *self.in_use.get_mut() = false;
+        *self.state.get_mut() = EMPTY;
+    }
+}
+
+impl<T> Default for OneShotChannelWithPanic<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<T> Drop for OneShotChannelWithPanic<T> {
     fn drop(&mut self) {
-        if *self.ready.get_mut() {
+        if *self.state.get_mut() == READY {
             unsafe { self.message.get_mut().assume_init_drop() }
         }
     }
@@ -91,52 +512,129 @@ unsafe impl<T> Sync for OneShotChannelWithPanic<T> where T: Send {}
 /// 这里不用再public了
 struct OneShotChannelWithArc<T> {
     message: UnsafeCell<MaybeUninit<T>>,
-    ready: AtomicBool,
+    state: AtomicU8,
+    // sender drop了，send过的话ready在这之前就已经设好了
+    disconnected: AtomicBool,
+    // receive_timeout用它计时，None就是真实时间
+    clock: Option<Arc<dyn Clock>>,
 }
 
 impl<T> OneShotChannelWithArc<T> {
+    /// 调用channel()的线程就是接收线程，send时会unpark它
     pub fn channel() -> (SenderWithArc<T>, ReceiverWithArc<T>) {
+        Self::channel_with_clock(None)
+    }
+
+    /// 和channel一样，receive_timeout按clock计时，None就是真实时间
+    pub fn channel_with_clock(
+        clock: Option<Arc<dyn Clock>>,
+    ) -> (SenderWithArc<T>, ReceiverWithArc<T>) {
         let a = Arc::new(OneShotChannelWithArc {
             message: UnsafeCell::new(MaybeUninit::uninit()),
-            ready: AtomicBool::new(false),
+            state: AtomicU8::new(EMPTY),
+            disconnected: AtomicBool::new(false),
+            clock,
         });
         (
-            SenderWithArc { channel: a.clone() },
-            ReceiverWithArc { channel: a },
+            SenderWithArc {
+                channel: a.clone(),
+                receiving_thread: thread::current(),
+            },
+            ReceiverWithArc {
+                channel: a,
+                _no_send: PhantomData,
+            },
         )
     }
 }
 unsafe impl<T> Sync for OneShotChannelWithArc<T> where T: Send {}
 pub struct SenderWithArc<T> {
     channel: Arc<OneShotChannelWithArc<T>>,
+    receiving_thread: Thread,
 }
 
 impl<T> SenderWithArc<T> {
     pub fn send(self, message: T) {
         unsafe { (*self.channel.message.get()).write(message) };
-        self.channel.ready.store(true, Ordering::Release);
+        self.channel.state.store(READY, Ordering::Release);
+        self.receiving_thread.unpark();
+    }
+}
+
+impl<T> Drop for SenderWithArc<T> {
+    /// send之后也会走到这里，receiver先看state，所以不会把已经发出的消息当成断开
+    fn drop(&mut self) {
+        self.channel.disconnected.store(true, Ordering::Release);
+        self.receiving_thread.unpark();
     }
 }
+
+/// sender没有send就drop了
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+/// 只有调用channel()的线程会被unpark，所以receiver是!Send的，只能在这个线程上receive
 pub struct ReceiverWithArc<T> {
     channel: Arc<OneShotChannelWithArc<T>>,
+    // 只有创建channel的线程会被unpark，所以不能交给别的线程
+    _no_send: PhantomData<*const ()>,
 }
 
 impl<T> ReceiverWithArc<T> {
     pub fn is_ready(&self) -> bool {
-        self.channel.ready.load(Ordering::Relaxed)
+        self.channel.state.load(Ordering::Relaxed) == READY
+    }
+
+    /// 不阻塞，和OneShotChannelWithPanic::receive一样返回NotReady或者AlreadyConsumed。
+    /// sender没有send就drop了也是NotReady，用receive可以区分出来
+    pub fn try_receive(&self) -> Result<T, OneShotError> {
+        take_message(&self.channel.state)?;
+        Ok(unsafe { (*self.channel.message.get()).assume_init_read() })
+    }
+
+    /// 还没有消息就park，被唤醒后重新检查，防止虚假唤醒。
+    /// sender没有send就drop了(或者消息已经被try_receive取走了)的话返回Err(Disconnected)
+    pub fn receive(self) -> Result<T, Disconnected> {
+        loop {
+            if let Some(r) = self.try_take() {
+                return r;
+            }
+            thread::park();
+        }
+    }
+
+    /// 最多等timeout，超时把receiver原样还回去，可以再等或者直接drop。
+    /// sender已经断开的时候也马上还回去，之后receive会返回Err(Disconnected)
+    pub fn receive_timeout(self, timeout: Duration) -> Result<T, Self> {
+        let clock = self.channel.clock.as_deref();
+        let deadline = clock_now(clock) + timeout;
+        loop {
+            match self.try_take() {
+                Some(Ok(message)) => return Ok(message),
+                Some(Err(Disconnected)) => return Err(self),
+                None => {}
+            }
+            let now = clock_now(clock);
+            if now >= deadline {
+                return Err(self);
+            }
+            thread::park_timeout(clock_wait_slice(clock, deadline - now));
+        }
     }
-    pub fn receive(self) -> T {
-        // 这里panic是防止未初始化，也就是防止receive在send之前调用
-        if !self.channel.ready.swap(false, Ordering::Acquire) {
-            panic!("no message available!");
+
+    fn try_take(&self) -> Option<Result<T, Disconnected>> {
+        // 先读disconnected：Acquire之后sender drop之前设好的state一定可见
+        let disconnected = self.channel.disconnected.load(Ordering::Acquire);
+        match self.try_receive() {
+            Ok(message) => Some(Ok(message)),
+            Err(_) => disconnected.then_some(Err(Disconnected)),
         }
-       unsafe { (*self.channel.message.get()).assume_init_read() } 
     }
 }
 
 impl<T> Drop for OneShotChannelWithArc<T> {
     fn drop(&mut self) {
-        if *self.ready.get_mut() {
+        if *self.state.get_mut() == READY {
             unsafe { self.message.get_mut().assume_init_drop() }
         }
     }
@@ -146,12 +644,12 @@ unsafe impl<T> Sync for OneShotChannelWithBorrows<T> where T: Send {}
 
 pub struct OneShotChannelWithBorrows<T> {
     message: UnsafeCell<MaybeUninit<T>>,
-    ready: AtomicBool,
+    state: AtomicU8,
 }
 
 impl<T> OneShotChannelWithBorrows<T> {
     pub fn new() -> Self {
-        Self { message: UnsafeCell::new(MaybeUninit::uninit()), ready: AtomicBool::new(false) }
+        Self { message: UnsafeCell::new(MaybeUninit::uninit()), state: AtomicU8::new(EMPTY) }
     }
 
     pub fn split<'a>(&'a mut self) -> (SenderWithBorrows<'a, T>, ReceiverWithBorrows<'a, T>) {
@@ -160,6 +658,12 @@ impl<T> OneShotChannelWithBorrows<T> {
     }
 }
 
+impl<T> Default for OneShotChannelWithBorrows<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct SenderWithBorrows<'a, T> {
     channel: &'a OneShotChannelWithBorrows<T>,
     // 为了unpark对应的线程
@@ -169,7 +673,7 @@ pub struct SenderWithBorrows<'a, T> {
 impl<T> SenderWithBorrows<'_, T> {
     pub fn send(self, message: T) {
         unsafe { (*self.channel.message.get()).write(message) };
-        self.channel.ready.store(true, Ordering::Release);
+        self.channel.state.store(READY, Ordering::Release);
         self.receving_thread.unpark();
     }
 }
@@ -182,19 +686,29 @@ pub struct ReceiverWithBorrows<'a, T> {
 
 impl<T> ReceiverWithBorrows<'_, T> {
     pub fn is_ready(&self) -> bool {
-        self.channel.ready.load(Ordering::Relaxed)
+        self.channel.state.load(Ordering::Relaxed) == READY
     }
 
-    pub fn receive(self) -> T {
-        if !self.channel.ready.swap(false, Ordering::Acquire) {
-            thread::park();
+    /// 不阻塞，和OneShotChannelWithPanic::receive一样返回NotReady或者AlreadyConsumed
+    pub fn try_receive(&self) -> Result<T, OneShotError> {
+        take_message(&self.channel.state)?;
+        Ok(unsafe { (*self.channel.message.get()).assume_init_read() })
+    }
+
+    /// NotReady的时候park等待，消息已经被try_receive取走了就返回AlreadyConsumed
+    pub fn receive(self) -> Result<T, OneShotError> {
+        loop {
+            match self.try_receive() {
+                // park可能虚假唤醒，醒来之后还要再检查
+                Err(OneShotError::NotReady) => thread::park(),
+                r => return r,
+            }
         }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
     }
 }
 impl<T> Drop for OneShotChannelWithBorrows<T> {
     fn drop(&mut self) {
-        if *self.ready.get_mut() {
+        if *self.state.get_mut() == READY {
             unsafe { self.message.get_mut().assume_init_drop() }
 } 
     }
@@ -202,76 +716,2996 @@ impl<T> Drop for OneShotChannelWithBorrows<T> {
 
 
 
-fn main() {}
-
-#[cfg(test)]
-mod test {
-    use std::thread;
+/// 基于credit的流控：消费者通过request(n)授权生产者再发送n条消息，
+/// credit用完之后send会阻塞，比按容量做的背压更严格
+struct CreditChannel<T> {
+    queue: Channel<T>,
+    credits: AtomicUsize,
+    // credit为0时生产者在这里等待，request之后唤醒
+    credit_lock: Mutex<()>,
+    credit_ready: Condvar,
+}
 
-    use super::*;
+pub fn credit_channel<T>() -> (CreditSender<T>, CreditReceiver<T>) {
+    let a = Arc::new(CreditChannel {
+        queue: Channel::new(),
+        credits: AtomicUsize::new(0),
+        credit_lock: Mutex::new(()),
+        credit_ready: Condvar::new(),
+    });
+    (
+        CreditSender { channel: a.clone() },
+        CreditReceiver { channel: a },
+    )
+}
 
-    #[test]
-    fn mutex_channel_works() {}
+pub struct CreditSender<T> {
+    channel: Arc<CreditChannel<T>>,
+}
 
-    #[test]
-    fn one_shot_channel_with_panic_works() {
-        let channel = OneShotChannelWithPanic::new();
-        let t = thread::current();
-        thread::scope(|s| {
-            s.spawn(|| {
-                channel.send("hello world!");
-                t.unpark();
-            });
-            while !channel.is_ready() {
-                thread::park();
+impl<T> CreditSender<T> {
+    /// 消耗一个credit，没有credit的时候阻塞直到消费者request
+    pub fn send(&self, message: T) {
+        let c = &self.channel;
+        let mut n = c.credits.load(Ordering::Relaxed);
+        loop {
+            if n == 0 {
+                // 在锁里检查再wait，request先加credit再拿锁notify，所以不会丢失唤醒
+                let g = c.credit_lock.lock().unwrap();
+                let _g = c
+                    .credit_ready
+                    .wait_while(g, |_| c.credits.load(Ordering::Relaxed) == 0)
+                    .unwrap();
+                n = c.credits.load(Ordering::Relaxed);
+                continue;
             }
-            println!("Hello, world!");
-            assert_eq!(channel.receive(), "hello world!");
-        });
+            match c
+                .credits
+                .compare_exchange_weak(n, n - 1, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(e) => n = e,
+            }
+        }
+        c.queue.send(message);
     }
+}
 
-    #[test]
-    fn one_shot_channel_with_arc_works() {
-        thread::scope(|s| {
-            let (sender, receiver) = OneShotChannelWithArc::channel();
-            let t = thread::current();
-            s.spawn(move || {
-                sender.send("hello world!");
-                t.unpark();
-            });
-            while !receiver.is_ready() {
-                thread::park();
-            }
-            assert_eq!(receiver.receive(), "hello world!");
-        });
+pub struct CreditReceiver<T> {
+    channel: Arc<CreditChannel<T>>,
+}
+
+impl<T> CreditReceiver<T> {
+    /// 授权生产者再发送n条消息
+    pub fn request(&self, n: usize) {
+        let c = &self.channel;
+        c.credits.fetch_add(n, Ordering::Relaxed);
+        drop(c.credit_lock.lock().unwrap());
+        c.credit_ready.notify_all();
     }
 
-    #[test]
-    fn one_shot_channel_with_borrow_works() {
-        let mut channel = OneShotChannelWithBorrows::new();
-        thread::scope(|s| {
-            let (sender, receiver) = channel.split();
-            let t = thread::current();
-            s.spawn(move || {
-                sender.send("hello world!");
-                t.unpark();
-            });
-            while !receiver.is_ready() {
-                thread::park();
-            }
-            assert_eq!(receiver.receive(), "hello world!");
-        });
+    pub fn receive(&self) -> T {
+        self.channel.queue.receive()
     }
+}
 
-    #[test]
-    fn one_shot_channel_with_borrow_block_works() {
-        let mut channel = OneShotChannelWithBorrows::new();
-        thread::scope(|s| {
-            let (sender, receiver) = channel.split();
-            s.spawn(move || {
-                sender.send("hello world!");
-            });
-            assert_eq!(receiver.receive(), "hello world!");
-        });
+/// 超过interval没有收到任何消息(包括生产者专门发的心跳消息)时返回的事件，
+/// 和断开不同，调用方可以据此做处理然后继续接收
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Heartbeat {
+    Stalled,
+}
+
+pub struct HeartbeatReceiver<'a, T> {
+    channel: &'a Channel<T>,
+    interval: Duration,
+}
+
+impl<'a, T> HeartbeatReceiver<'a, T> {
+    pub fn new(channel: &'a Channel<T>, interval: Duration) -> Self {
+        Self { channel, interval }
+    }
+
+    pub fn recv_with_heartbeat(&self) -> Result<T, Heartbeat> {
+        // pop_within会处理虚假唤醒，并且总等待时间不超过interval
+        self.channel
+            .pop_within(self.interval)
+            .ok_or(Heartbeat::Stalled)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    /// 等到超时队列还是满的，消息原样还回来
+    Timeout(T),
+    /// 所有接收者都已经drop了，再等也没人收，消息原样还回来
+    Disconnected(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// 队列满了，消息原样还回来，可以拿着它重试
+    Full(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+}
+
+/// 能被cancel唤醒的阻塞点，cancel的时候需要拿到它的锁再notify_all
+trait Wake {
+    fn wake_all(&self);
+}
+
+impl<T> Wake for Channel<T> {
+    fn wake_all(&self) {
+        // 拿一次锁，保证接收者要么还没检查token，要么已经在wait里了
+        drop(self.queue.lock().unwrap());
+        self.item_ready.notify_all();
+    }
+}
+
+/// 协作式取消，cancel之后所有在recv_cancellable里阻塞的接收者都会被唤醒
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<TokenInner>,
+}
+
+#[derive(Default)]
+struct TokenInner {
+    cancelled: AtomicBool,
+    // 当前阻塞在这个token上的channel
+    waiters: Mutex<Vec<*const (dyn Wake + 'static)>>,
+}
+
+// Safety: waiters里的指针只在注册期间被解引用，注销要拿waiters的锁，
+// 而注册的channel都要求T: Send，所以可以在别的线程上调用wake_all
+unsafe impl Send for TokenInner {}
+unsafe impl Sync for TokenInner {}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::Release);
+        for &waker in self.inner.waiters.lock().unwrap().iter() {
+            // Safety: the Registration removes the pointer under this lock
+            // before the channel it points to can go away.
+            unsafe { (*waker).wake_all() };
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    fn register<'a>(&'a self, waker: &'a (dyn Wake + 'a)) -> Registration<'a> {
+        // Safety: Registration的drop会在'a结束之前把指针删掉
+        let waker = unsafe {
+            std::mem::transmute::<*const (dyn Wake + 'a), *const (dyn Wake + 'static)>(waker)
+        };
+        self.inner.waiters.lock().unwrap().push(waker);
+        Registration {
+            token: &self.inner,
+            waker,
+        }
+    }
+}
+
+struct Registration<'a> {
+    token: &'a TokenInner,
+    waker: *const (dyn Wake + 'static),
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.token.waiters.lock().unwrap();
+        let i = waiters
+            .iter()
+            .position(|&w| std::ptr::addr_eq(w, self.waker))
+            .unwrap();
+        waiters.swap_remove(i);
+    }
+}
+
+/// 带确认的channel：send返回一个AckReceiver，消费者处理完调用ack之后生产者才会收到确认，
+/// 消息没有ack就被drop的话生产者收到Nack
+pub struct AckChannel<T> {
+    queue: Channel<AckMessage<T>>,
+}
+
+impl<T> AckChannel<T> {
+    pub fn new() -> Self {
+        Self {
+            queue: Channel::new(),
+        }
+    }
+
+    pub fn send(&self, message: T) -> AckReceiver {
+        let (sender, receiver) = OneShotChannelWithArc::channel();
+        self.queue.send(AckMessage {
+            message,
+            ack: Some(sender),
+        });
+        AckReceiver { receiver }
+    }
+
+    pub fn receive(&self) -> AckMessage<T> {
+        self.queue.receive()
+    }
+}
+
+impl<T> Default for AckChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct AckMessage<T> {
+    message: T,
+    // 内嵌的one-shot，true表示ack，false表示没有ack就被drop了
+    // send时会unpark创建它的生产者线程
+    ack: Option<SenderWithArc<bool>>,
+}
+
+impl<T> AckMessage<T> {
+    pub fn ack(mut self) {
+        self.complete(true);
+    }
+
+    fn complete(&mut self, acked: bool) {
+        if let Some(sender) = self.ack.take() {
+            sender.send(acked);
+        }
+    }
+}
+
+impl<T> Deref for AckMessage<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.message
+    }
+}
+
+impl<T> Drop for AckMessage<T> {
+    fn drop(&mut self) {
+        self.complete(false);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nack;
+
+pub struct AckReceiver {
+    receiver: ReceiverWithArc<bool>,
+}
+
+impl AckReceiver {
+    /// 阻塞到消费者ack或者drop了这条消息
+    pub fn wait(self) -> Result<(), Nack> {
+        // AckMessage drop的时候一定会发false，不会走到Disconnected
+        if self.receiver.receive() == Ok(true) {
+            Ok(())
+        } else {
+            Err(Nack)
+        }
+    }
+}
+
+/// 请求/响应：call把请求和一个one-shot一起发给服务端，然后阻塞在one-shot上等回复
+pub struct RpcChannel<Req, Resp> {
+    queue: Channel<RpcRequest<Req, Resp>>,
+}
+
+impl<Req, Resp> RpcChannel<Req, Resp> {
+    pub fn new() -> Self {
+        Self {
+            queue: Channel::new(),
+        }
+    }
+
+    /// 阻塞到服务端回复。请求没有handle就被drop、handle里的f panic了，
+    /// 或者channel带着没处理的请求被drop了，都返回Err(Disconnected)
+    pub fn call(&self, request: Req) -> Result<Resp, Disconnected> {
+        // one-shot在调用call的线程上创建，回复的时候会unpark这个线程
+        let (responder, receiver) = OneShotChannelWithArc::channel();
+        self.queue.send(RpcRequest { request, responder });
+        receiver.receive()
+    }
+
+    /// 服务端取下一个请求，没有就阻塞
+    pub fn receive(&self) -> RpcRequest<Req, Resp> {
+        self.queue.receive()
+    }
+}
+
+impl<Req, Resp> Default for RpcChannel<Req, Resp> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 服务端收到的请求，调用handle回复；没有回复就drop的话call会收到Disconnected
+#[must_use]
+pub struct RpcRequest<Req, Resp> {
+    request: Req,
+    // 没有send就drop的时候one-shot会通知call断开了
+    responder: SenderWithArc<Resp>,
+}
+
+impl<Req, Resp> RpcRequest<Req, Resp> {
+    pub fn handle(self, f: impl FnOnce(Req) -> Resp) {
+        // f panic的话responder在展开的时候drop，一样会通知call
+        self.responder.send(f(self.request));
+    }
+}
+
+/// 把channel接到io上：写进来的字节按块变成Vec<u8>消息发出去。
+/// channel本身没有断开的概念，所以最后一个writer drop的时候发一个空块表示EOF。
+/// 要多个线程往同一个流里写就clone，它们共享一个计数，中间有writer先drop也不会提前结束ChannelReader。
+/// 一个channel上只能new一次：每次new都是单独的计数，会各自发EOF
+pub struct ChannelWriter<'a> {
+    channel: &'a Channel<Vec<u8>>,
+    // 同一个流里还活着的writer个数
+    writers: Arc<AtomicUsize>,
+}
+
+impl<'a> ChannelWriter<'a> {
+    pub fn new(channel: &'a Channel<Vec<u8>>) -> Self {
+        Self {
+            channel,
+            writers: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+}
+
+impl Clone for ChannelWriter<'_> {
+    fn clone(&self) -> Self {
+        self.writers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            channel: self.channel,
+            writers: self.writers.clone(),
+        }
+    }
+}
+
+impl Write for ChannelWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // 空块是EOF，不能发出去
+        if !buf.is_empty() {
+            self.channel.send(buf.to_vec());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ChannelWriter<'_> {
+    fn drop(&mut self) {
+        // AcqRel: 别的writer在各自drop之前发的块一定排在EOF前面
+        if self.writers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.channel.send(Vec::new());
+        }
+    }
+}
+
+pub struct ChannelReader<'a> {
+    channel: &'a Channel<Vec<u8>>,
+    // 当前正在读的块和读到的位置
+    chunk: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<'a> ChannelReader<'a> {
+    pub fn new(channel: &'a Channel<Vec<u8>>) -> Self {
+        Self {
+            channel,
+            chunk: Vec::new(),
+            pos: 0,
+            eof: false,
+        }
+    }
+}
+
+impl Read for ChannelReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() && !self.eof && !buf.is_empty() {
+            self.chunk = self.channel.receive();
+            self.pos = 0;
+            self.eof = self.chunk.is_empty();
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// 最多缓存这么多个回收的Vec，避免空闲的时候一直占着内存
+const MAX_RECYCLED: usize = 16;
+
+/// 专门传字节的channel：消费者用完的Vec通过recycle还回来，
+/// send优先复用这些Vec而不是重新分配
+pub struct BufferChannel {
+    channel: Channel<Vec<u8>>,
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferChannel {
+    pub fn new() -> Self {
+        Self {
+            channel: Channel::new(),
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn send(&self, bytes: &[u8]) {
+        let mut buf = self.free.lock().unwrap().pop().unwrap_or_default();
+        buf.extend_from_slice(bytes);
+        self.channel.send(buf);
+    }
+
+    pub fn receive(&self) -> Vec<u8> {
+        self.channel.receive()
+    }
+
+    /// 把用完的buffer还给channel，下次send的时候复用它的容量
+    pub fn recycle(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < MAX_RECYCLED {
+            free.push(buf);
+        }
+    }
+
+    /// 先把上一次的buffer还回去，再接收下一条
+    pub fn recv_into(&self, reuse: Vec<u8>) -> Vec<u8> {
+        self.recycle(reuse);
+        self.receive()
+    }
+}
+
+impl Default for BufferChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// demux的输出端，每一类消息对应一个channel
+pub struct Demux<K, T> {
+    outputs: Arc<Mutex<HashMap<K, Arc<Channel<T>>>>>,
+}
+
+impl<K: Eq + Hash, T> Demux<K, T> {
+    /// 拿到某一类消息的输出channel，还没有的话就先建一个，所以可以在消息到达之前就开始等
+    pub fn output(&self, key: K) -> Arc<Channel<T>> {
+        let mut outputs = self.outputs.lock().unwrap();
+        outputs
+            .entry(key)
+            .or_insert_with(|| Arc::new(Channel::new()))
+            .clone()
+    }
+}
+
+/// demux线程在source上每次最多等这么久，然后检查Demux是不是已经drop了
+const DEMUX_POLL: Duration = Duration::from_millis(10);
+
+/// 起一个后台线程不停地从source读消息，用classify分类后转发到对应的输出channel。
+/// Channel没有关闭的概念，所以线程隔一段时间检查一次，返回的Demux drop之后就退出，
+/// 还没读的消息留在source里。JoinHandle可以用来等它退出
+pub fn demux<T, K, F>(source: Arc<Channel<T>>, classify: F) -> (Demux<K, T>, JoinHandle<()>)
+where
+    T: Send + 'static,
+    K: Eq + Hash + Send + 'static,
+    F: Fn(&T) -> K + Send + 'static,
+{
+    let demux = Demux {
+        outputs: Arc::new(Mutex::new(HashMap::new())),
+    };
+    let outputs = demux.outputs.clone();
+    let handle = thread::spawn(move || {
+        let demux = Demux { outputs };
+        // 只剩线程自己的这一份，说明返回出去的Demux已经drop了
+        while Arc::strong_count(&demux.outputs) > 1 {
+            if let Ok(message) = source.receive_timeout(DEMUX_POLL) {
+                demux.output(classify(&message)).send(message);
+            }
+        }
+    });
+    (demux, handle)
+}
+
+/// 往多个channel发一组相关的消息，要么全部可见要么全部不可见。
+/// send只是先存起来，commit的时候才真正放进队列，没有commit就drop的话消息全部丢弃
+#[must_use = "staged messages are discarded unless the transaction is committed"]
+pub struct Transaction<'a, T> {
+    staged: Vec<(&'a Channel<T>, Vec<T>)>,
+}
+
+impl<'a, T> Transaction<'a, T> {
+    pub fn new() -> Self {
+        Self { staged: Vec::new() }
+    }
+
+    pub fn send(&mut self, channel: &'a Channel<T>, message: T) {
+        match self
+            .staged
+            .iter_mut()
+            .find(|(c, _)| std::ptr::eq(*c, channel))
+        {
+            Some((_, pending)) => pending.push(message),
+            None => self.staged.push((channel, vec![message])),
+        }
+    }
+
+    /// 先把涉及到的channel全部锁上再放消息，所以receiver不会只看到其中一部分。
+    /// 按地址顺序加锁，两个事务同时commit也不会死锁
+    pub fn commit(mut self) {
+        self.staged.sort_by_key(|&(c, _)| c as *const Channel<T>);
+        let mut queues: Vec<_> = self
+            .staged
+            .iter()
+            .map(|&(c, _)| c.queue.lock().unwrap())
+            .collect();
+        let mut evicted = Vec::new();
+        for ((c, pending), q) in self.staged.iter_mut().zip(&mut queues) {
+            evicted.extend(pending.drain(..).filter_map(|m| c.push_locked(q, m)));
+        }
+        drop(queues);
+        for (c, _) in &self.staged {
+            c.item_ready.notify_all();
+            if let Some(n) = &c.notifier {
+                n.notify();
+            }
+        }
+        drop(evicted);
+    }
+}
+
+impl<T> Default for Transaction<'_, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 只有一个槽位的channel，可以反复使用：send等到槽空了才放进去，receive等到槽满了才取出来，
+/// 生产者最多只能领先消费者一条消息
+pub struct HandoffChannel<T> {
+    slot: Mutex<Option<T>>,
+    // 槽里放进了值
+    filled: Condvar,
+    // 槽里的值被取走了
+    emptied: Condvar,
+}
+
+impl<T> HandoffChannel<T> {
+    pub fn new() -> Self {
+        Self {
+            slot: Mutex::new(None),
+            filled: Condvar::new(),
+            emptied: Condvar::new(),
+        }
+    }
+
+    pub fn send(&self, message: T) {
+        let slot = self.slot.lock().unwrap();
+        let mut slot = self.emptied.wait_while(slot, |s| s.is_some()).unwrap();
+        *slot = Some(message);
+        drop(slot);
+        self.filled.notify_one();
+    }
+
+    pub fn receive(&self) -> T {
+        let slot = self.slot.lock().unwrap();
+        let mut slot = self.filled.wait_while(slot, |s| s.is_none()).unwrap();
+        let message = slot.take().unwrap();
+        drop(slot);
+        self.emptied.notify_one();
+        message
+    }
+}
+
+impl<T> Default for HandoffChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 有容量上限的channel，队列满了send会阻塞，直到消费者取走消息腾出空间
+pub struct BoundedChannel<T> {
+    queue: Mutex<VecDeque<T>>,
+    item_ready: Condvar,
+    space_ready: Condvar,
+    cap: usize,
+    high_water: AtomicUsize,
+    // 活着的BoundedReceiver数量，在队列的锁里修改
+    receivers: AtomicUsize,
+    // 用过receiver()并且所有BoundedReceiver都drop了，在队列的锁里置位
+    disconnected: AtomicBool,
+    // poll_send返回Pending时交给channel的消息和它的waker，按先后顺序等空位。
+    // 锁的顺序总是先queue再parked
+    parked: Mutex<VecDeque<(T, Waker)>>,
+    // send_timeout用它计时，None就是真实时间
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl<T> BoundedChannel<T> {
+    pub fn with_capacity(cap: usize) -> Self {
+        assert!(cap > 0, "capacity must be non-zero");
+        Self {
+            queue: Mutex::new(VecDeque::with_capacity(cap)),
+            item_ready: Condvar::new(),
+            space_ready: Condvar::new(),
+            cap,
+            high_water: AtomicUsize::new(0),
+            receivers: AtomicUsize::new(0),
+            disconnected: AtomicBool::new(false),
+            parked: Mutex::new(VecDeque::new()),
+            clock: None,
+        }
+    }
+
+    pub fn with_capacity_and_clock(cap: usize, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock: Some(clock),
+            ..Self::with_capacity(cap)
+        }
+    }
+
+    /// 在队列的锁里调用
+    fn push_locked(&self, queue: &mut VecDeque<T>, message: T) {
+        queue.push_back(message);
+        self.high_water.fetch_max(queue.len(), Ordering::Relaxed);
+    }
+
+    pub fn send(&self, message: T) {
+        let b = self.queue.lock().unwrap();
+        let mut b = self
+            .space_ready
+            .wait_while(b, |q| q.len() >= self.cap)
+            .unwrap();
+        self.push_locked(&mut b, message);
+        drop(b);
+        self.item_ready.notify_one();
+    }
+
+    /// 和try_send_timeout一样
+    pub fn send_timeout(&self, message: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.try_send_timeout(message, timeout)
+    }
+
+    /// 按channel的Clock最多等timeout，一直满着就返回Timeout；
+    /// 所有BoundedReceiver都drop了就马上返回Disconnected，生产者可以分清是消费者慢还是消费者没了。
+    /// 两种情况消息都原样还回来
+    pub fn try_send_timeout(
+        &self,
+        message: T,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError<T>> {
+        let clock = self.clock.as_deref();
+        let deadline = clock_now(clock) + timeout;
+        let mut b = self.queue.lock().unwrap();
+        loop {
+            if self.disconnected.load(Ordering::Relaxed) {
+                return Err(SendTimeoutError::Disconnected(message));
+            }
+            if b.len() < self.cap {
+                break;
+            }
+            let now = clock_now(clock);
+            if now >= deadline {
+                return Err(SendTimeoutError::Timeout(message));
+            }
+            let slice = clock_wait_slice(clock, deadline - now);
+            b = self.space_ready.wait_timeout(b, slice).unwrap().0;
+        }
+        self.push_locked(&mut b, message);
+        drop(b);
+        self.item_ready.notify_one();
+        Ok(())
+    }
+
+    /// 不阻塞，满了就把消息还回来
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        let mut b = self.queue.lock().unwrap();
+        if b.len() >= self.cap {
+            return Err(TrySendError::Full(message));
+        }
+        self.push_locked(&mut b, message);
+        drop(b);
+        self.item_ready.notify_one();
+        Ok(())
+    }
+
+    /// 给异步的生产者用：有空位就直接放进队列；满了就把消息和waker一起交给channel，返回Pending，
+    /// 等receive腾出空位的时候由它把消息放进队列，再wake。所以Pending的时候消息已经算发出去了，
+    /// 和已经在队列里的消息一样，之后所有接收者都drop的话会被直接drop掉。
+    /// 已经断开的话消息原样还回来
+    pub fn poll_send(&self, message: T, waker: &Waker) -> Poll<Result<(), SendError<T>>> {
+        let mut b = self.queue.lock().unwrap();
+        if self.disconnected.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(SendError(message)));
+        }
+        // 有消息在parked里排队的时候队列一定是满的，见freed_slot
+        if b.len() < self.cap {
+            self.push_locked(&mut b, message);
+            drop(b);
+            self.item_ready.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+        self.parked
+            .lock()
+            .unwrap()
+            .push_back((message, waker.clone()));
+        Poll::Pending
+    }
+
+    /// 取走一条消息之后在队列的锁里调用：空出来的位置先给poll_send交过来的消息，
+    /// 返回它的waker在锁外wake；没有的话通知阻塞的send
+    fn freed_slot(&self, queue: &mut VecDeque<T>) -> Option<Waker> {
+        let (message, waker) = self.parked.lock().unwrap().pop_front()?;
+        self.push_locked(queue, message);
+        Some(waker)
+    }
+
+    fn after_pop(&self, waker: Option<Waker>) {
+        match waker {
+            Some(waker) => waker.wake(),
+            None => self.space_ready.notify_one(),
+        }
+    }
+
+    /// 不阻塞，没有消息就返回Empty
+    pub fn try_receive(&self) -> Result<T, TryRecvError> {
+        let mut b = self.queue.lock().unwrap();
+        let message = b.pop_front().ok_or(TryRecvError::Empty)?;
+        let waker = self.freed_slot(&mut b);
+        drop(b);
+        self.after_pop(waker);
+        Ok(message)
+    }
+
+    pub fn receive(&self) -> T {
+        let b = self.queue.lock().unwrap();
+        let mut b = self.item_ready.wait_while(b, |q| q.is_empty()).unwrap();
+        let message = b.pop_front().unwrap();
+        let waker = self.freed_slot(&mut b);
+        drop(b);
+        self.after_pop(waker);
+        message
+    }
+
+    /// 队列是不是已经满了。只是加锁那一刻的快照，返回之后别的线程可能马上就改变了它，
+    /// 只能用来决定要不要先去做别的事，不能保证接下来的send不阻塞
+    pub fn is_full(&self) -> bool {
+        self.queue.lock().unwrap().len() >= self.cap
+    }
+
+    /// 现在send会不会阻塞，和is_full一样只是快照
+    pub fn would_block_send(&self) -> bool {
+        self.is_full()
+    }
+
+    /// 拿一个接收端的句柄，可以clone。用过这个之后，所有BoundedReceiver都drop了channel就算断开，
+    /// try_send_timeout会返回Disconnected；之后再拿新的BoundedReceiver也不会重新连上
+    pub fn receiver(&self) -> BoundedReceiver<'_, T> {
+        let b = self.queue.lock().unwrap();
+        self.receivers.fetch_add(1, Ordering::Relaxed);
+        drop(b);
+        BoundedReceiver { channel: self }
+    }
+
+    /// 队列曾经达到过的最大长度，队列清空以后也不会回落。
+    /// 一直等于容量的话说明生产者经常被挡住，可以考虑调大容量
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+}
+
+pub struct BoundedReceiver<'a, T> {
+    channel: &'a BoundedChannel<T>,
+}
+
+impl<T> BoundedReceiver<'_, T> {
+    pub fn receive(&self) -> T {
+        self.channel.receive()
+    }
+
+    pub fn try_receive(&self) -> Result<T, TryRecvError> {
+        self.channel.try_receive()
+    }
+}
+
+impl<T> Clone for BoundedReceiver<'_, T> {
+    fn clone(&self) -> Self {
+        self.channel.receiver()
+    }
+}
+
+impl<T> Drop for BoundedReceiver<'_, T> {
+    fn drop(&mut self) {
+        let c = self.channel;
+        let b = c.queue.lock().unwrap();
+        if c.receivers.fetch_sub(1, Ordering::Relaxed) == 1 {
+            c.disconnected.store(true, Ordering::Relaxed);
+            let parked = mem::take(&mut *c.parked.lock().unwrap());
+            drop(b);
+            // 在满队列上等着的生产者都要醒过来看到断开
+            c.space_ready.notify_all();
+            for (message, waker) in parked {
+                drop(message);
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// channel已经close了，没发出去的消息原样还回来
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// 所有Sender都drop了，并且队列已经空了
+    Disconnected,
+}
+
+type CountHook = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// Sender或Receiver句柄的数量，计数变化的时候调用注册的回调
+struct HandleCount {
+    count: AtomicUsize,
+    // 锁只用来取出回调，回调本身在锁外调用，所以回调里可以clone/drop句柄或者重新注册
+    hook: Mutex<Option<CountHook>>,
+    // 没注册过回调的时候不用碰锁
+    has_hook: AtomicBool,
+}
+
+impl HandleCount {
+    fn new() -> Self {
+        Self {
+            count: AtomicUsize::new(1),
+            hook: Mutex::new(None),
+            has_hook: AtomicBool::new(false),
+        }
+    }
+
+    fn load(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    fn increment(&self) {
+        let n = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        self.notify(n);
+    }
+
+    /// 返回减完之后的数量
+    fn decrement(&self) -> usize {
+        let n = self.count.fetch_sub(1, Ordering::Release) - 1;
+        self.notify(n);
+        n
+    }
+
+    fn notify(&self, n: usize) {
+        if !self.has_hook.load(Ordering::Acquire) {
+            return;
+        }
+        let hook = self.hook.lock().unwrap().clone();
+        if let Some(f) = hook {
+            f(n);
+        }
+    }
+
+    fn set_hook(&self, f: impl Fn(usize) + Send + Sync + 'static) {
+        *self.hook.lock().unwrap() = Some(Arc::new(f));
+        self.has_hook.store(true, Ordering::Release);
+    }
+}
+
+struct SplitChannel<T> {
+    channel: Channel<T>,
+    // 活着的Sender数量，变成0之后receive不再阻塞
+    senders: HandleCount,
+    receivers: HandleCount,
+    // 在队列的锁里置位
+    closed: AtomicBool,
+}
+
+impl<T> SplitChannel<T> {
+    fn close(&self) {
+        let b = self.channel.queue.lock().unwrap();
+        self.closed.store(true, Ordering::Relaxed);
+        drop(b);
+        self.channel.item_ready.notify_all();
+    }
+}
+
+/// 和Channel一样的队列，但是Receiver能知道所有Sender都已经没了，而不是永远阻塞下去
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let a = Arc::new(SplitChannel {
+        channel: Channel::new(),
+        senders: HandleCount::new(),
+        receivers: HandleCount::new(),
+        closed: AtomicBool::new(false),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+pub struct Sender<T> {
+    channel: Arc<SplitChannel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// 只有channel被close之后才会失败
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let c = &self.channel;
+        c.channel
+            .send_unless_closed(message, &c.closed)
+            .map_err(SendError)
+    }
+
+    /// 不等别的Sender drop，马上关闭channel：之后所有Sender的send都会失败，
+    /// receiver收完已经在队列里的消息之后返回Disconnected
+    pub fn close(self) {
+        self.channel.close();
+    }
+
+    /// 监控用：之后每次Sender被clone或drop都会用新的数量调用f，替换掉之前注册的回调。
+    /// f在锁外调用，多个线程同时clone/drop的时候收到的数量可能乱序
+    pub fn on_sender_count_change(&self, f: impl Fn(usize) + Send + Sync + 'static) {
+        self.channel.senders.set_hook(f);
+    }
+
+    /// 同on_sender_count_change，只是监控的是Receiver
+    pub fn on_receiver_count_change(&self, f: impl Fn(usize) + Send + Sync + 'static) {
+        self.channel.receivers.set_hook(f);
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.increment();
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.decrement() == 0 {
+            // 拿一下队列的锁再notify，receiver要么还没检查计数，要么已经在wait了，不会丢失唤醒
+            let c = &self.channel.channel;
+            drop(c.queue.lock().unwrap());
+            c.item_ready.notify_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<SplitChannel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// 队列里还有消息的时候总是先返回消息，所有Sender都drop之后发的消息也不会丢
+    pub fn receive(&self) -> Result<T, RecvError> {
+        let c = &self.channel;
+        let b = c.channel.queue.lock().unwrap();
+        let mut b = c
+            .channel
+            .item_ready
+            .wait_while(b, |q| {
+                q.is_empty() && c.senders.load() > 0 && !c.closed.load(Ordering::Relaxed)
+            })
+            .unwrap();
+        b.pop_front().ok_or(RecvError::Disconnected)
+    }
+
+    /// 关闭channel，然后在budget之内尽量把队列里剩下的消息取出来。
+    /// 每条消息单独拿一次锁，超时之后还没取的消息留在队列里，最后一个句柄drop的时候一起drop
+    pub fn shutdown(&self, budget: Duration) -> Vec<T> {
+        let c = &self.channel;
+        c.close();
+        let deadline = c.channel.now() + budget;
+        let mut drained = Vec::new();
+        while c.channel.now() < deadline {
+            match c.channel.queue.lock().unwrap().pop_front() {
+                Some(message) => drained.push(message),
+                None => break,
+            }
+        }
+        drained
+    }
+
+    pub fn on_sender_count_change(&self, f: impl Fn(usize) + Send + Sync + 'static) {
+        self.channel.senders.set_hook(f);
+    }
+
+    pub fn on_receiver_count_change(&self, f: impl Fn(usize) + Send + Sync + 'static) {
+        self.channel.receivers.set_hook(f);
+    }
+}
+
+/// 多个Receiver之间每条消息只会被其中一个收到
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.increment();
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receivers.decrement();
+    }
+}
+
+/// for循环一直收到所有Sender都drop并且队列空了为止
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter { receiver: self }
+    }
+}
+
+pub struct IntoIter<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive().ok()
+    }
+}
+
+/// 几个channel共享的通知。每个channel的item_ready是各自的Condvar，没办法同时等两个，
+/// 所以send的时候额外递增这里的计数，select2就只需要在这一个Condvar上等
+pub struct Notifier {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self {
+            generation: Mutex::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+
+    fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// 等到计数不再是seen，也就是seen之后至少有过一次send
+    fn wait_past(&self, seen: u64) {
+        let g = self.generation.lock().unwrap();
+        let _g = self.changed.wait_while(g, |g| *g == seen).unwrap();
+    }
+
+    /// 和wait_past一样，但是最多等timeout
+    fn wait_past_timeout(&self, seen: u64, timeout: Duration) {
+        let g = self.generation.lock().unwrap();
+        let _g = self
+            .changed
+            .wait_timeout_while(g, timeout, |g| *g == seen)
+            .unwrap();
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// 阻塞到a或b有消息，两边都有的时候优先a。两个channel必须用同一个Notifier构造。
+/// 先记下计数再检查两个队列，检查之后的send一定会改变计数，所以不会丢失唤醒
+pub fn select2<A, B>(a: &Channel<A>, b: &Channel<B>) -> Either<A, B> {
+    let notifier = match (&a.notifier, &b.notifier) {
+        (Some(x), Some(y)) if Arc::ptr_eq(x, y) => x,
+        _ => panic!("select2 needs both channels to share a Notifier"),
+    };
+    loop {
+        let seen = notifier.generation();
+        if let Some(message) = a.try_receive() {
+            return Either::Left(message);
+        }
+        if let Some(message) = b.try_receive() {
+            return Either::Right(message);
+        }
+        notifier.wait_past(seen);
+    }
+}
+
+impl<T> Channel<T> {
+    /// select!展开之后用的
+    #[doc(hidden)]
+    pub fn select_parts(&self) -> SelectParts<'_> {
+        (self.notifier.as_ref(), self.clock.as_deref())
+    }
+}
+
+#[doc(hidden)]
+pub type SelectParts<'a> = (Option<&'a Arc<Notifier>>, Option<&'a dyn Clock>);
+
+/// select!展开之后用的等待部分：检查所有channel之前先记下计数，和select2一样不会丢失唤醒。
+/// 超时按第一个channel的Clock算
+#[doc(hidden)]
+pub struct SelectWait<'a> {
+    notifier: &'a Notifier,
+    clock: Option<&'a dyn Clock>,
+    deadline: Option<Instant>,
+}
+
+impl<'a> SelectWait<'a> {
+    pub fn new(parts: &[SelectParts<'a>], timeout: Option<Duration>) -> Self {
+        let (notifier, clock) = parts[0];
+        let notifier = notifier.expect("select! needs channels built with a Notifier");
+        let shared = |p: &SelectParts| p.0.is_some_and(|n| Arc::ptr_eq(n, notifier));
+        assert!(
+            parts.iter().all(shared),
+            "select! needs all channels to share a Notifier"
+        );
+        Self {
+            notifier,
+            clock,
+            deadline: timeout.map(|t| clock_now(clock) + t),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.notifier.generation()
+    }
+
+    /// 等到seen之后有新的send，或者等了一段。返回false表示已经到了deadline，该走timeout分支了
+    pub fn wait(&self, seen: u64) -> bool {
+        let Some(deadline) = self.deadline else {
+            self.notifier.wait_past(seen);
+            return true;
+        };
+        let now = clock_now(self.clock);
+        if now >= deadline {
+            return false;
+        }
+        let slice = clock_wait_slice(self.clock, deadline - now);
+        self.notifier.wait_past_timeout(seen, slice);
+        true
+    }
+}
+
+/// 在几个用同一个Notifier构造的Channel上等任意一个有消息:
+///
+/// ```ignore
+/// select! {
+///     x = a.receive() => { ... },
+///     y = b.receive() => { ... },
+///     timeout(Duration::from_millis(10)) => { ... },
+/// }
+/// ```
+///
+/// 最后可以有一个timeout(d)分支，等了d还没有消息就执行它；或者一个default分支，没有消息马上执行它。
+/// 都没有的话一直等下去。每个调用点记着一个轮转的起点，每次从下一个channel开始检查，
+/// 几个channel一直都有消息的时候不会总是第一个赢。channel只能写成一个变量名
+#[macro_export]
+macro_rules! select {
+    (@parse [$($arms:tt)*] default => $fallback:block $(,)?) => {
+        $crate::select!(@emit [$($arms)*] (Some(::std::time::Duration::ZERO)) $fallback)
+    };
+    (@parse [$($arms:tt)*] timeout($timeout:expr) => $fallback:block $(,)?) => {
+        $crate::select!(@emit [$($arms)*] (Some($timeout)) $fallback)
+    };
+    (@parse [$($arms:tt)*] $pat:pat = $ch:ident . receive() => $body:block $(, $($rest:tt)*)?) => {
+        $crate::select!(@parse [$($arms)* ($pat, $ch, $body)] $($($rest)*)?)
+    };
+    (@parse [$($arms:tt)*]) => {
+        $crate::select!(@emit [$($arms)*] (None))
+    };
+    (@emit [$(($pat:pat, $ch:ident, $body:block))+] ($timeout:expr) $($fallback:block)?) => {{
+        static NEXT: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+        let wait = $crate::SelectWait::new(&[$($ch.select_parts()),+], $timeout);
+        let n = [$(stringify!($ch)),+].len();
+        let start = NEXT.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed) % n;
+        #[allow(unused_assignments)]
+        let result = 'select: loop {
+            let seen = wait.generation();
+            for i in 0..n {
+                let index = (start + i) % n;
+                let mut k = 0;
+                $(
+                    if k == index {
+                        if let Some($pat) = $ch.try_receive() {
+                            break 'select ($body);
+                        }
+                    }
+                    k += 1;
+                )+
+            }
+            // 没有timeout/default分支的时候wait永远返回true
+            if !wait.wait(seen) {
+                $(break 'select ($fallback);)?
+            }
+        };
+        result
+    }};
+    ($($arms:tt)+) => {
+        $crate::select!(@parse [] $($arms)+)
+    };
+}
+
+/// 每个订阅者都能收到每一条消息：send的时候给每个订阅者的队列放一份clone。
+/// 订阅之前发的消息不会收到
+pub struct BroadcastChannel<T> {
+    state: Mutex<BroadcastState<T>>,
+    item_ready: Condvar,
+}
+
+struct BroadcastState<T> {
+    next_id: u64,
+    queues: HashMap<u64, VecDeque<T>>,
+}
+
+impl<T: Clone> BroadcastChannel<T> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(BroadcastState {
+                next_id: 0,
+                queues: HashMap::new(),
+            }),
+            item_ready: Condvar::new(),
+        }
+    }
+
+    pub fn subscribe(&self) -> Subscriber<'_, T> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queues.insert(id, VecDeque::new());
+        Subscriber { channel: self, id }
+    }
+
+    pub fn send(&self, message: T) {
+        let mut state = self.state.lock().unwrap();
+        for queue in state.queues.values_mut() {
+            queue.push_back(message.clone());
+        }
+        drop(state);
+        // 每个订阅者等的是自己的队列，只能全部叫醒
+        self.item_ready.notify_all();
+    }
+}
+
+impl<T: Clone> Default for BroadcastChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Subscriber<'a, T> {
+    channel: &'a BroadcastChannel<T>,
+    id: u64,
+}
+
+impl<T> Subscriber<'_, T> {
+    pub fn receive(&self) -> T {
+        let state = self.channel.state.lock().unwrap();
+        let mut state = self
+            .channel
+            .item_ready
+            .wait_while(state, |s| s.queues[&self.id].is_empty())
+            .unwrap();
+        state.queues.get_mut(&self.id).unwrap().pop_front().unwrap()
+    }
+}
+
+impl<T> Drop for Subscriber<'_, T> {
+    fn drop(&mut self) {
+        // 没收的消息跟着队列一起drop
+        let queue = self.channel.state.lock().unwrap().queues.remove(&self.id);
+        drop(queue);
+    }
+}
+
+/// 按批次确认的流控：消费者一次最多拿到window条，ack_batch之后才能拿下一批；
+/// 已经发出但还没被确认的消息达到window条时，生产者阻塞
+pub struct WindowedChannel<T> {
+    state: Mutex<WindowState<T>>,
+    item_ready: Condvar,
+    acked: Condvar,
+    window: usize,
+}
+
+struct WindowState<T> {
+    queue: VecDeque<T>,
+    // 已经send但还没被ack的消息数，包括还在队列里的
+    unacked: usize,
+}
+
+impl<T> WindowedChannel<T> {
+    pub fn new(window: usize) -> Self {
+        assert!(window > 0, "window must be non-zero");
+        Self {
+            state: Mutex::new(WindowState {
+                queue: VecDeque::new(),
+                unacked: 0,
+            }),
+            item_ready: Condvar::new(),
+            acked: Condvar::new(),
+            window,
+        }
+    }
+
+    /// 只能有一个WindowedReceiver，否则确认的计数就对不上了
+    pub fn split(&mut self) -> (WindowedSender<'_, T>, WindowedReceiver<'_, T>) {
+        let channel = &*self;
+        (
+            WindowedSender { channel },
+            WindowedReceiver {
+                channel,
+                delivered: None,
+            },
+        )
+    }
+}
+
+pub struct WindowedSender<'a, T> {
+    channel: &'a WindowedChannel<T>,
+}
+
+impl<T> WindowedSender<'_, T> {
+    pub fn send(&self, message: T) {
+        let c = self.channel;
+        let state = c.state.lock().unwrap();
+        let mut state = c
+            .acked
+            .wait_while(state, |s| s.unacked >= c.window)
+            .unwrap();
+        state.queue.push_back(message);
+        state.unacked += 1;
+        drop(state);
+        c.item_ready.notify_one();
+    }
+}
+
+pub struct WindowedReceiver<'a, T> {
+    channel: &'a WindowedChannel<T>,
+    // 上一批的条数，ack之前是Some
+    delivered: Option<usize>,
+}
+
+impl<T> WindowedReceiver<'_, T> {
+    /// 阻塞到至少有一条消息，然后取走最多window条
+    pub fn receive_batch(&mut self) -> Vec<T> {
+        assert!(
+            self.delivered.is_none(),
+            "the previous batch must be acked first"
+        );
+        let c = self.channel;
+        let state = c.state.lock().unwrap();
+        let mut state = c
+            .item_ready
+            .wait_while(state, |s| s.queue.is_empty())
+            .unwrap();
+        let n = state.queue.len().min(c.window);
+        let batch: Vec<T> = state.queue.drain(..n).collect();
+        self.delivered = Some(n);
+        batch
+    }
+
+    /// 确认上一批已经处理完，释放对应的窗口，让阻塞的生产者继续
+    pub fn ack_batch(&mut self) {
+        let n = self.delivered.take().expect("no batch to ack");
+        self.channel.state.lock().unwrap().unacked -= n;
+        self.channel.acked.notify_all();
+    }
+}
+
+/// 每个key只保留最新的值：慢的消费者只会看到每个key最后一次send的值，但是每个key都能看到。
+/// 位置按key第一次进入队列的时候算，后来的send只替换值不改变位置，
+/// 所以一个一直在更新的key也不会被不停地挤到后面
+pub struct ConflatingChannel<K, V> {
+    state: Mutex<ConflatingState<K, V>>,
+    item_ready: Condvar,
+}
+
+struct ConflatingState<K, V> {
+    order: VecDeque<K>,
+    values: HashMap<K, V>,
+}
+
+impl<K: Eq + Hash + Clone, V> ConflatingChannel<K, V> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(ConflatingState {
+                order: VecDeque::new(),
+                values: HashMap::new(),
+            }),
+            item_ready: Condvar::new(),
+        }
+    }
+
+    pub fn send(&self, key: K, value: V) {
+        let mut state = self.state.lock().unwrap();
+        let old = state.values.insert(key.clone(), value);
+        if old.is_none() {
+            state.order.push_back(key);
+        }
+        drop(state);
+        self.item_ready.notify_one();
+        // 被覆盖的旧值在锁外面drop
+        drop(old);
+    }
+
+    pub fn receive(&self) -> (K, V) {
+        let state = self.state.lock().unwrap();
+        let mut state = self
+            .item_ready
+            .wait_while(state, |s| s.order.is_empty())
+            .unwrap();
+        let key = state.order.pop_front().unwrap();
+        let value = state.values.remove(&key).unwrap();
+        (key, value)
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> Default for ConflatingChannel<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 优先级高的先收到，优先级一样的按发送顺序
+pub struct PriorityChannel<T> {
+    state: Mutex<PriorityState<T>>,
+    item_ready: Condvar,
+}
+
+struct PriorityState<T> {
+    heap: BinaryHeap<Prioritized<T>>,
+    // 每条消息一个递增的序号，优先级相同时序号小的先出来
+    next_seq: u64,
+}
+
+struct Prioritized<T> {
+    priority: u8,
+    seq: u64,
+    message: T,
+}
+
+// 只比较(priority, seq)，T不需要实现Ord
+impl<T> Ord for Prioritized<T> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl<T> PartialOrd for Prioritized<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for Prioritized<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl<T> Eq for Prioritized<T> {}
+
+impl<T> PriorityChannel<T> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(PriorityState {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+            }),
+            item_ready: Condvar::new(),
+        }
+    }
+
+    pub fn send(&self, message: T, priority: u8) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.heap.push(Prioritized {
+            priority,
+            seq,
+            message,
+        });
+        drop(state);
+        self.item_ready.notify_one();
+    }
+
+    pub fn receive(&self) -> T {
+        let state = self.state.lock().unwrap();
+        let mut state = self
+            .item_ready
+            .wait_while(state, |s| s.heap.is_empty())
+            .unwrap();
+        state.heap.pop().unwrap().message
+    }
+}
+
+impl<T> Default for PriorityChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 多个receiver严格轮流收消息：按register的顺序，第n条消息一定给轮到的那个receiver。
+/// 轮到的receiver不来收，后面的就都得等着，不想再收了就drop掉它，让出位置
+pub struct RoundRobinChannel<T> {
+    state: Mutex<RoundRobinState<T>>,
+    // 有新消息或者轮到的人变了
+    changed: Condvar,
+}
+
+struct RoundRobinState<T> {
+    queue: VecDeque<T>,
+    // 按注册顺序排列的receiver id
+    receivers: Vec<u64>,
+    turn: usize,
+    next_id: u64,
+}
+
+impl<T> RoundRobinChannel<T> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(RoundRobinState {
+                queue: VecDeque::new(),
+                receivers: Vec::new(),
+                turn: 0,
+                next_id: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn register(&self) -> RoundRobinReceiver<'_, T> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.receivers.push(id);
+        RoundRobinReceiver { channel: self, id }
+    }
+
+    pub fn send(&self, message: T) {
+        self.state.lock().unwrap().queue.push_back(message);
+        // 只有轮到的那个能拿，不知道它是谁，只能全部叫醒
+        self.changed.notify_all();
+    }
+}
+
+impl<T> Default for RoundRobinChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct RoundRobinReceiver<'a, T> {
+    channel: &'a RoundRobinChannel<T>,
+    id: u64,
+}
+
+impl<T> RoundRobinReceiver<'_, T> {
+    pub fn receive(&self) -> T {
+        let c = self.channel;
+        let state = c.state.lock().unwrap();
+        let mut state = c
+            .changed
+            .wait_while(state, |s| {
+                s.queue.is_empty() || s.receivers[s.turn] != self.id
+            })
+            .unwrap();
+        let message = state.queue.pop_front().unwrap();
+        state.turn = (state.turn + 1) % state.receivers.len();
+        drop(state);
+        c.changed.notify_all();
+        message
+    }
+}
+
+impl<T> Drop for RoundRobinReceiver<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock().unwrap();
+        let i = state
+            .receivers
+            .iter()
+            .position(|&id| id == self.id)
+            .unwrap();
+        state.receivers.remove(i);
+        // 排在自己后面的往前挪了一位，轮次也跟着挪；轮到最后一个之后回到开头
+        if i < state.turn {
+            state.turn -= 1;
+        }
+        if state.turn >= state.receivers.len() {
+            state.turn = 0;
+        }
+        drop(state);
+        self.channel.changed.notify_all();
+    }
+}
+
+fn main() {
+    let (sender, receiver) = OneShotChannelWithArc::channel();
+    thread::spawn(move || sender.send("hello world!"));
+    while !receiver.is_ready() {
+        std::hint::spin_loop();
+    }
+    assert_eq!(receiver.receive(), Ok("hello world!"));
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn mutex_channel_works() {}
+
+    #[test]
+    fn one_shot_channel_with_panic_works() {
+        let channel = OneShotChannelWithPanic::new();
+        let t = thread::current();
+        thread::scope(|s| {
+            s.spawn(|| {
+                channel.send("hello world!");
+                t.unpark();
+            });
+            while !channel.is_ready() {
+                thread::park();
+            }
+            println!("Hello, world!");
+            assert_eq!(channel.receive_unchecked(), "hello world!");
+        });
+    }
+
+    #[test]
+    fn one_shot_channel_with_arc_works() {
+        thread::scope(|s| {
+            let (sender, receiver) = OneShotChannelWithArc::channel();
+            let t = thread::current();
+            s.spawn(move || {
+                sender.send("hello world!");
+                t.unpark();
+            });
+            while !receiver.is_ready() {
+                thread::park();
+            }
+            assert_eq!(receiver.receive(), Ok("hello world!"));
+        });
+    }
+
+    #[test]
+    fn one_shot_channel_with_arc_receive_timeout() {
+        let (sender, receiver) = OneShotChannelWithArc::<i32>::channel();
+        let start = Instant::now();
+        let receiver = receiver
+            .receive_timeout(Duration::from_millis(30))
+            .unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            sender.send(7);
+        });
+        assert_eq!(
+            receiver.receive_timeout(Duration::from_secs(5)).ok(),
+            Some(7)
+        );
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn one_shot_channel_with_borrow_works() {
+        let mut channel = OneShotChannelWithBorrows::new();
+        thread::scope(|s| {
+            let (sender, receiver) = channel.split();
+            let t = thread::current();
+            s.spawn(move || {
+                sender.send("hello world!");
+                t.unpark();
+            });
+            while !receiver.is_ready() {
+                thread::park();
+            }
+            assert_eq!(receiver.receive(), Ok("hello world!"));
+        });
+    }
+
+    #[test]
+    fn one_shot_channel_with_borrow_block_works() {
+        let mut channel = OneShotChannelWithBorrows::new();
+        thread::scope(|s| {
+            let (sender, receiver) = channel.split();
+            s.spawn(move || {
+                sender.send("hello world!");
+            });
+            assert_eq!(receiver.receive(), Ok("hello world!"));
+        });
+    }
+
+    #[test]
+    fn one_shot_channel_with_borrow_spurious_wakeup() {
+        let mut channel = OneShotChannelWithBorrows::new();
+        for i in 0..100 {
+            thread::scope(|s| {
+                let (sender, receiver) = channel.split();
+                // 先留一个unpark的token，receive里第一次park会直接返回，相当于虚假唤醒
+                thread::current().unpark();
+                s.spawn(move || {
+                    thread::yield_now();
+                    sender.send(i);
+                });
+                assert_eq!(receiver.receive(), Ok(i));
+            });
+        }
+    }
+
+    #[test]
+    fn credit_channel_blocks_without_credit() {
+        let (sender, receiver) = credit_channel();
+        let sent = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                sender.send(1);
+                sent.store(true, Ordering::Relaxed);
+                sender.send(2);
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!sent.load(Ordering::Relaxed));
+            receiver.request(1);
+            assert_eq!(receiver.receive(), 1);
+            receiver.request(1);
+            assert_eq!(receiver.receive(), 2);
+        });
+        assert!(sent.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn heartbeat_receiver_reports_stall() {
+        let channel = Channel::new();
+        let receiver = HeartbeatReceiver::new(&channel, Duration::from_millis(20));
+        channel.send(1);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                channel.send(2);
+            });
+            assert_eq!(receiver.recv_with_heartbeat(), Ok(1));
+            assert_eq!(receiver.recv_with_heartbeat(), Err(Heartbeat::Stalled));
+            let mut r = receiver.recv_with_heartbeat();
+            while r == Err(Heartbeat::Stalled) {
+                r = receiver.recv_with_heartbeat();
+            }
+            assert_eq!(r, Ok(2));
+        });
+    }
+
+    #[test]
+    fn recv_array_works() {
+        let channel = Channel::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..4 {
+                    thread::sleep(Duration::from_millis(5));
+                    channel.send(i);
+                }
+            });
+            assert_eq!(channel.recv_array::<4>(), [0, 1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn drop_oldest_evicts_front() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct DetectDrop(i32);
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let channel = Channel::new_drop_oldest(2);
+        channel.send(DetectDrop(1));
+        channel.send(DetectDrop(2));
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        channel.send(DetectDrop(3));
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+        assert_eq!(channel.receive().0, 2);
+        assert_eq!(channel.receive().0, 3);
+    }
+
+    #[test]
+    fn recv_cancellable_wakes_on_cancel() {
+        let channel = Channel::<i32>::new();
+        let token = CancellationToken::new();
+        thread::scope(|s| {
+            let t = s.spawn(|| channel.recv_cancellable(&token));
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+            assert_eq!(t.join().unwrap(), Err(Cancelled));
+        });
+        assert!(token.inner.waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recv_cancellable_receives() {
+        let channel = Channel::new();
+        let token = CancellationToken::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                channel.send(1);
+            });
+            assert_eq!(channel.recv_cancellable(&token), Ok(1));
+        });
+    }
+
+    #[test]
+    fn drain_each_works() {
+        let channel = Channel::new();
+        for i in 1..=3 {
+            channel.send(i);
+        }
+        let mut seen = Vec::new();
+        channel.drain_each(|m| seen.push(m));
+        assert_eq!(seen, [1, 2, 3]);
+    }
+
+    #[test]
+    fn ack_channel_works() {
+        let channel = AckChannel::<&str>::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let m = channel.receive();
+                assert_eq!(*m, "job");
+                m.ack();
+                // 没有ack就drop，生产者收到Nack
+                drop(channel.receive());
+            });
+            assert_eq!(channel.send("job").wait(), Ok(()));
+            assert_eq!(channel.send("dropped").wait(), Err(Nack));
+        });
+    }
+
+    #[test]
+    fn rpc_channel_works() {
+        let rpc = RpcChannel::<u32, String>::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..3 {
+                    rpc.receive().handle(|n| format!("echo {n}"));
+                }
+            });
+            for n in 0..3 {
+                assert_eq!(rpc.call(n), Ok(format!("echo {n}")));
+            }
+        });
+    }
+
+    #[test]
+    fn rpc_call_disconnects_without_reply() {
+        let rpc = RpcChannel::<u32, u32>::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                // 没有handle就drop
+                drop(rpc.receive());
+                // handle的时候panic
+                let r = rpc.receive();
+                let panicked = thread::scope(|s| s.spawn(|| r.handle(|_| panic!())).join());
+                assert!(panicked.is_err());
+            });
+            assert_eq!(rpc.call(1), Err(Disconnected));
+            assert_eq!(rpc.call(2), Err(Disconnected));
+        });
+
+        // channel带着没处理的请求被drop
+        let (responder, receiver) = OneShotChannelWithArc::channel();
+        let rpc = RpcChannel::<u32, u32>::new();
+        rpc.queue.send(RpcRequest {
+            request: 3,
+            responder,
+        });
+        drop(rpc);
+        assert_eq!(receiver.receive(), Err(Disconnected));
+    }
+
+    #[test]
+    fn per_producer_fifo() {
+        const PRODUCERS: usize = 4;
+        const MESSAGES: usize = 1000;
+        let channel = Channel::new();
+        thread::scope(|s| {
+            for id in 0..PRODUCERS {
+                let channel = &channel;
+                s.spawn(move || {
+                    for seq in 0..MESSAGES {
+                        channel.send((id, seq));
+                    }
+                });
+            }
+            let mut next = [0; PRODUCERS];
+            for _ in 0..PRODUCERS * MESSAGES {
+                let (id, seq) = channel.receive();
+                assert_eq!(seq, next[id]);
+                next[id] += 1;
+            }
+            assert_eq!(next, [MESSAGES; PRODUCERS]);
+        });
+    }
+
+    #[test]
+    fn recv_with_lag_works() {
+        let channel = Channel::new();
+        for i in 0..5 {
+            channel.send(i);
+        }
+        assert_eq!(channel.recv_with_lag(), (0, 4));
+    }
+
+    #[test]
+    fn channel_io_works() {
+        let channel = Channel::new();
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut writer = ChannelWriter::new(&channel);
+                for chunk in data.chunks(333) {
+                    writer.write_all(chunk).unwrap();
+                }
+            });
+            let mut out = Vec::new();
+            ChannelReader::new(&channel).read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
+        });
+    }
+
+    #[test]
+    fn channel_io_waits_for_last_writer() {
+        let channel = Channel::new();
+        let first = ChannelWriter::new(&channel);
+        let second = first.clone();
+        thread::scope(|s| {
+            s.spawn(move || {
+                let mut first = first;
+                first.write_all(b"abc").unwrap();
+            })
+            .join()
+            .unwrap();
+            // 第一个writer已经drop了，还没有EOF
+            assert_eq!(channel.len(), 1);
+            s.spawn(move || {
+                let mut second = second;
+                second.write_all(b"def").unwrap();
+            });
+            let mut out = Vec::new();
+            ChannelReader::new(&channel).read_to_end(&mut out).unwrap();
+            assert_eq!(out, b"abcdef");
+        });
+    }
+
+    #[test]
+    fn buffer_channel_recycles() {
+        let channel = BufferChannel::new();
+        channel.send(b"hello");
+        let mut buf = channel.receive();
+        let mut ptrs = std::collections::HashSet::new();
+        for i in 0..10u8 {
+            channel.send(&[i; 5]);
+            buf = channel.recv_into(buf);
+            assert_eq!(buf, [i; 5]);
+            ptrs.insert(buf.as_ptr());
+        }
+        // 同时在用的buffer最多两个(一个在队列里，一个在手上)，之后都是复用
+        assert_eq!(ptrs.len(), 2);
+    }
+
+    #[test]
+    fn high_water_mark_works() {
+        let channel = Channel::new();
+        assert_eq!(channel.high_water_mark(), 0);
+        for i in 0..3 {
+            channel.send(i);
+        }
+        channel.receive();
+        channel.send(3);
+        for _ in 0..3 {
+            channel.receive();
+        }
+        assert_eq!(channel.high_water_mark(), 3);
+    }
+
+    #[test]
+    fn demux_works() {
+        let source = Arc::new(Channel::new());
+        let (outputs, handle) = demux(source.clone(), |n: &i32| n % 2 == 0);
+        for i in 0..10 {
+            source.send(i);
+        }
+        let even = outputs.output(true);
+        let odd = outputs.output(false);
+        assert_eq!(even.recv_array::<5>(), [0, 2, 4, 6, 8]);
+        assert_eq!(odd.recv_array::<5>(), [1, 3, 5, 7, 9]);
+        // Demux drop之后线程退出，也不再拿着source
+        drop(outputs);
+        handle.join().unwrap();
+        assert_eq!(Arc::strong_count(&source), 1);
+    }
+
+    #[test]
+    fn transaction_works() {
+        let a = Channel::new();
+        let b = Channel::new();
+        let mut tx = Transaction::new();
+        tx.send(&a, 1);
+        tx.send(&b, 2);
+        tx.send(&a, 3);
+        assert!(a.queue.lock().unwrap().is_empty());
+        assert!(b.queue.lock().unwrap().is_empty());
+        tx.commit();
+        assert_eq!(a.recv_array::<2>(), [1, 3]);
+        assert_eq!(b.receive(), 2);
+
+        let mut tx = Transaction::new();
+        tx.send(&a, 4);
+        drop(tx);
+        assert!(a.queue.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recv_with_tick_works() {
+        let channel = Channel::new();
+        let mut ticks = 0;
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(200));
+                channel.send(42);
+            });
+            let message = channel.recv_with_tick(Duration::from_millis(20), || ticks += 1);
+            assert_eq!(message, 42);
+        });
+        assert!(ticks >= 2, "only {ticks} ticks");
+    }
+
+    #[test]
+    fn handoff_channel_works() {
+        let channel = HandoffChannel::new();
+        let sent = AtomicUsize::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    channel.send(i);
+                    sent.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            for i in 0..100 {
+                assert_eq!(channel.receive(), i);
+                // 取走i之后生产者最多再放进去i+1，i+2要等下一次receive
+                assert!(sent.load(Ordering::SeqCst) <= i + 2);
+            }
+        });
+    }
+
+    #[test]
+    fn try_receive_works() {
+        let channel = Channel::new();
+        assert_eq!(channel.try_receive(), None);
+        channel.send(1);
+        assert_eq!(channel.try_receive(), Some(1));
+        assert_eq!(channel.try_receive(), None);
+    }
+
+    #[test]
+    fn try_receive_with_concurrent_producer() {
+        let channel = Channel::new();
+        assert_eq!(channel.try_receive(), None);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..3 {
+                    channel.send(i);
+                }
+            });
+        });
+        let mut received = Vec::new();
+        while let Some(i) = channel.try_receive() {
+            received.push(i);
+        }
+        assert_eq!(received, [0, 1, 2]);
+    }
+
+    #[test]
+    fn receive_timeout_works() {
+        let channel = Channel::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                channel.send(1);
+            });
+            assert_eq!(channel.receive_timeout(Duration::from_millis(200)), Ok(1));
+        });
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                channel.send(2);
+            });
+            assert_eq!(
+                channel.receive_timeout(Duration::from_millis(10)),
+                Err(RecvTimeoutError::Timeout)
+            );
+        });
+    }
+
+    #[test]
+    fn notify_all_wakes_every_consumer() {
+        let channel = Channel::new_with_notify(NotifyStrategy::NotifyAll);
+        thread::scope(|s| {
+            let pair = s.spawn(|| channel.recv_array::<2>());
+            let single = s.spawn(|| channel.receive());
+            thread::sleep(Duration::from_millis(50));
+            // 不管先唤醒的是谁，receive都会被叫醒拿到这条消息
+            channel.send(1);
+            assert_eq!(single.join().unwrap(), 1);
+            channel.send(2);
+            channel.send(3);
+            assert_eq!(pair.join().unwrap(), [2, 3]);
+        });
+    }
+
+    #[test]
+    fn len_and_is_empty_work() {
+        let channel = Channel::new();
+        assert!(channel.is_empty());
+        for i in 0..3 {
+            channel.send(i);
+        }
+        assert_eq!(channel.len(), 3);
+        assert!(!channel.is_empty());
+        while channel.try_receive().is_some() {}
+        assert_eq!(channel.len(), 0);
+        assert!(channel.is_empty());
+    }
+
+    #[test]
+    fn bounded_channel_applies_backpressure() {
+        let channel = BoundedChannel::with_capacity(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    channel.send(i);
+                }
+            });
+            for i in 0..100 {
+                if i % 10 == 0 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                assert!(channel.queue.lock().unwrap().len() <= 2);
+                assert_eq!(channel.receive(), i);
+            }
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity must be non-zero")]
+    fn bounded_channel_rejects_zero_capacity() {
+        BoundedChannel::<i32>::with_capacity(0);
+    }
+
+    #[test]
+    fn bounded_channel_is_full() {
+        let channel = BoundedChannel::with_capacity(1);
+        assert!(!channel.is_full());
+        assert!(!channel.would_block_send());
+        channel.send(1);
+        assert!(channel.is_full());
+        assert!(channel.would_block_send());
+        assert_eq!(channel.receive(), 1);
+        assert!(!channel.is_full());
+        assert!(!channel.would_block_send());
+    }
+
+    #[test]
+    fn poll_send_completes_after_receive() {
+        struct WakeFlag(AtomicBool);
+        impl std::task::Wake for WakeFlag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+        let flag = Arc::new(WakeFlag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let channel = BoundedChannel::with_capacity(1);
+        let receiver = channel.receiver();
+        assert_eq!(channel.poll_send(0, &waker), Poll::Ready(Ok(())));
+        assert_eq!(channel.poll_send(1, &waker), Poll::Pending);
+        assert!(!flag.0.load(Ordering::Relaxed));
+        // 腾出的空位直接给了挂起的消息，然后wake
+        assert_eq!(receiver.receive(), 0);
+        assert!(flag.0.load(Ordering::Relaxed));
+        assert!(channel.is_full());
+        assert_eq!(receiver.try_receive(), Ok(1));
+        assert_eq!(receiver.try_receive(), Err(TryRecvError::Empty));
+
+        drop(receiver);
+        assert_eq!(channel.poll_send(2, &waker), Poll::Ready(Err(SendError(2))));
+    }
+
+    #[test]
+    fn try_send_timeout_succeeds_when_space_frees_up() {
+        let channel = BoundedChannel::with_capacity(1);
+        let receiver = channel.receiver();
+        channel.send(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                assert_eq!(receiver.receive(), 0);
+            });
+            assert_eq!(channel.try_send_timeout(1, Duration::from_secs(10)), Ok(()));
+        });
+        assert_eq!(receiver.try_receive(), Ok(1));
+    }
+
+    #[test]
+    fn try_send_timeout_times_out_while_full() {
+        let channel = BoundedChannel::with_capacity(1);
+        let _receiver = channel.receiver();
+        channel.send(0);
+        assert_eq!(
+            channel.try_send_timeout(1, Duration::from_millis(20)),
+            Err(SendTimeoutError::Timeout(1))
+        );
+    }
+
+    #[test]
+    fn try_send_timeout_reports_disconnect() {
+        let channel = BoundedChannel::with_capacity(1);
+        let receiver = channel.receiver();
+        let other = receiver.clone();
+        channel.send(0);
+        drop(other);
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                drop(receiver);
+            });
+            // 阻塞在满队列上的时候最后一个接收者drop了
+            assert_eq!(
+                channel.try_send_timeout(1, Duration::from_secs(10)),
+                Err(SendTimeoutError::Disconnected(1))
+            );
+        });
+        // 有空位也一样是断开
+        channel.try_receive().unwrap();
+        assert_eq!(
+            channel.try_send_timeout(2, Duration::ZERO),
+            Err(SendTimeoutError::Disconnected(2))
+        );
+    }
+
+    #[test]
+    fn bounded_channel_high_water_mark() {
+        let channel = BoundedChannel::with_capacity(8);
+        assert_eq!(channel.high_water_mark(), 0);
+        channel.send(0);
+        channel.try_send(1).unwrap();
+        channel.send_timeout(2, Duration::ZERO).unwrap();
+        channel.receive();
+        channel.send(3);
+        for _ in 0..3 {
+            channel.receive();
+        }
+        assert!(!channel.is_full());
+        assert_eq!(channel.high_water_mark(), 3);
+    }
+
+    #[test]
+    fn bounded_channel_try_send_full_try_receive_empty() {
+        let channel = BoundedChannel::with_capacity(1);
+        assert_eq!(channel.try_receive(), Err(TryRecvError::Empty));
+        assert_eq!(channel.try_send(1), Ok(()));
+        assert_eq!(channel.try_send(2), Err(TrySendError::Full(2)));
+        assert_eq!(channel.try_receive(), Ok(1));
+        assert_eq!(channel.try_receive(), Err(TryRecvError::Empty));
+
+        // 多个生产者和消费者都只用try_*，拿回来的值重试
+        let received = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for p in 0..3 {
+                let channel = &channel;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        let mut message = p * 100 + i;
+                        while let Err(TrySendError::Full(m)) = channel.try_send(message) {
+                            message = m;
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+            for _ in 0..3 {
+                s.spawn(|| {
+                    while received.load(Ordering::Relaxed) < 300 {
+                        match channel.try_receive() {
+                            Ok(_) => {
+                                received.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(TryRecvError::Empty) => thread::yield_now(),
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(received.load(Ordering::Relaxed), 300);
+        assert_eq!(channel.try_receive(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn recv_timed_works() {
+        let channel = Channel::new();
+        let delay = Duration::from_millis(50);
+        thread::scope(|s| {
+            s.spawn(|| {
+                // 多睡一倍，给spawn到调用recv_timed之间的时间留足余量
+                thread::sleep(delay * 2);
+                channel.send(1);
+            });
+            let (message, waited) = channel.recv_timed();
+            assert_eq!(message, 1);
+            assert!(waited >= delay);
+        });
+    }
+
+    #[test]
+    fn receiver_drains_before_disconnect() {
+        let (sender, receiver) = channel();
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        drop(sender);
+        assert_eq!(receiver.receive(), Ok(1));
+        assert_eq!(receiver.receive(), Ok(2));
+        assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn disconnect_after_all_cloned_senders_drop() {
+        let (sender, receiver) = channel();
+        thread::scope(|s| {
+            for i in 0..3 {
+                let sender = sender.clone();
+                s.spawn(move || {
+                    thread::sleep(Duration::from_millis(20));
+                    sender.send(i).unwrap();
+                });
+            }
+            drop(sender);
+            let mut received = Vec::new();
+            while let Ok(i) = receiver.receive() {
+                received.push(i);
+            }
+            received.sort();
+            assert_eq!(received, [0, 1, 2]);
+        });
+    }
+
+    #[test]
+    fn receiver_into_iter_ends_on_disconnect() {
+        let (sender, receiver) = channel();
+        thread::scope(|s| {
+            for t in 0..3 {
+                let sender = sender.clone();
+                s.spawn(move || {
+                    for i in 0..10 {
+                        sender.send(t * 10 + i).unwrap();
+                    }
+                });
+            }
+            drop(sender);
+            let mut received: Vec<_> = receiver.into_iter().collect();
+            received.sort();
+            assert!(received.into_iter().eq(0..30));
+        });
+    }
+
+    #[test]
+    fn peek_nth_works() {
+        let channel = Channel::new();
+        for i in ["a", "b", "c"] {
+            channel.send(i);
+        }
+        assert_eq!(channel.peek_nth(0), Some("a"));
+        assert_eq!(channel.peek_nth(2), Some("c"));
+        assert_eq!(channel.peek_nth(3), None);
+        assert_eq!(channel.recv_array::<3>(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn drain_works() {
+        let channel = Channel::new();
+        for i in 0..5 {
+            channel.send(i);
+        }
+        assert_eq!(channel.drain(), [0, 1, 2, 3, 4]);
+        assert!(channel.is_empty());
+        assert!(channel.drain().is_empty());
+    }
+
+    #[test]
+    fn select2_works() {
+        let notifier = Arc::new(Notifier::new());
+        let a = Channel::<i32>::with_notifier(notifier.clone());
+        let b = Channel::with_notifier(notifier);
+        b.send("b");
+        assert_eq!(select2(&a, &b), Either::Right("b"));
+        assert!(a.is_empty());
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                a.send(1);
+            });
+            assert_eq!(select2(&a, &b), Either::Left(1));
+        });
+    }
+
+    #[test]
+    fn select_macro_picks_ready_channel() {
+        let notifier = Arc::new(Notifier::new());
+        let a = Channel::<i32>::with_notifier(notifier.clone());
+        let b = Channel::<&str>::with_notifier(notifier);
+        b.send("b");
+        let got = select! {
+            x = a.receive() => { Either::Left(x) },
+            y = b.receive() => { Either::Right(y) },
+        };
+        assert_eq!(got, Either::Right("b"));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                a.send(1);
+            });
+            let got = select! {
+                x = a.receive() => { Either::Left(x) },
+                y = b.receive() => { Either::Right(y) },
+            };
+            assert_eq!(got, Either::Left(1));
+        });
+
+        // 两边一直都有消息的时候轮流赢
+        let mut wins = [0, 0];
+        for _ in 0..10 {
+            a.send(0);
+            b.send("");
+            select! {
+                _ = a.receive() => { wins[0] += 1 },
+                _ = b.receive() => { wins[1] += 1 },
+            }
+        }
+        assert_eq!(wins, [5, 5]);
+    }
+
+    #[test]
+    fn select_macro_timeout_and_default() {
+        let notifier = Arc::new(Notifier::new());
+        let a = Channel::<i32>::with_notifier(notifier.clone());
+        let b = Channel::<i32>::with_notifier(notifier);
+        let start = Instant::now();
+        let got = select! {
+            x = a.receive() => { Some(x) },
+            x = b.receive() => { Some(x) },
+            timeout(Duration::from_millis(20)) => { None },
+        };
+        assert_eq!(got, None);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let got = select! {
+            x = a.receive() => { Some(x) },
+            default => { None },
+        };
+        assert_eq!(got, None);
+        b.send(2);
+        let got = select! {
+            x = a.receive() => { Some(x) },
+            x = b.receive() => { Some(x) },
+            default => { None },
+        };
+        assert_eq!(got, Some(2));
+    }
+
+    #[test]
+    fn count_change_callbacks_fire() {
+        let senders = Arc::new(Mutex::new(Vec::new()));
+        let receivers = Arc::new(Mutex::new(Vec::new()));
+        let (sender, receiver) = channel::<i32>();
+        let log = senders.clone();
+        receiver.on_sender_count_change(move |n| log.lock().unwrap().push(n));
+        let log = receivers.clone();
+        sender.on_receiver_count_change(move |n| log.lock().unwrap().push(n));
+
+        let s2 = sender.clone();
+        let s3 = s2.clone();
+        drop(sender);
+        let r2 = receiver.clone();
+        drop(receiver);
+        drop((s2, s3));
+        drop(r2);
+        assert_eq!(*senders.lock().unwrap(), [2, 3, 2, 1, 0]);
+        assert_eq!(*receivers.lock().unwrap(), [2, 1, 0]);
+    }
+
+    #[test]
+    fn count_change_callback_can_reenter() {
+        let (sender, receiver) = channel::<i32>();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let r = receiver.clone();
+        let seen2 = seen.clone();
+        // 回调里再注册一个回调，并且clone/drop句柄，不会死锁
+        receiver.on_sender_count_change(move |_| {
+            let seen = seen2.clone();
+            r.on_sender_count_change(move |n| seen.store(n, Ordering::Relaxed));
+            drop(r.clone());
+        });
+        let s2 = sender.clone();
+        assert_eq!(seen.load(Ordering::Relaxed), 0);
+        drop(s2);
+        assert_eq!(seen.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn broadcast_channel_works() {
+        let channel = BroadcastChannel::new();
+        let a = channel.subscribe();
+        let b = channel.subscribe();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..3 {
+                    channel.send(i);
+                }
+            });
+            s.spawn(|| assert_eq!([a.receive(), a.receive(), a.receive()], [0, 1, 2]));
+            assert_eq!([b.receive(), b.receive(), b.receive()], [0, 1, 2]);
+        });
+        let late = channel.subscribe();
+        channel.send(3);
+        assert_eq!(late.receive(), 3);
+        drop(b);
+        assert_eq!(channel.state.lock().unwrap().queues.len(), 2);
+        assert_eq!(a.receive(), 3);
+    }
+
+    #[test]
+    fn send_after_close_returns_message() {
+        let (sender, receiver) = channel();
+        let other = sender.clone();
+        other.send(1).unwrap();
+        sender.close();
+        assert_eq!(other.send(2), Err(SendError(2)));
+        assert_eq!(receiver.receive(), Ok(1));
+        assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn close_wakes_blocked_receiver() {
+        let (sender, receiver) = channel::<i32>();
+        let other = sender.clone();
+        thread::scope(|s| {
+            let t = s.spawn(|| receiver.receive());
+            thread::sleep(Duration::from_millis(20));
+            sender.close();
+            assert_eq!(t.join().unwrap(), Err(RecvError::Disconnected));
+        });
+        drop(other);
+    }
+
+    #[test]
+    fn close_wakes_every_blocked_receiver() {
+        let (sender, receiver) = channel::<i32>();
+        let other = sender.clone();
+        thread::scope(|s| {
+            let threads: Vec<_> = (0..3)
+                .map(|_| {
+                    let receiver = receiver.clone();
+                    s.spawn(move || receiver.receive())
+                })
+                .collect();
+            thread::sleep(Duration::from_millis(20));
+            // close用的是notify_all，一次close三个receiver都要醒
+            let start = Instant::now();
+            sender.close();
+            for t in threads {
+                assert_eq!(t.join().unwrap(), Err(RecvError::Disconnected));
+            }
+            assert!(start.elapsed() < Duration::from_secs(1));
+        });
+        drop(other);
+    }
+
+    #[test]
+    fn shutdown_drains_within_budget() {
+        let (sender, receiver) = channel();
+        for i in 0..10 {
+            sender.send(i).unwrap();
+        }
+        assert_eq!(
+            receiver.shutdown(Duration::from_secs(1)),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert_eq!(sender.send(10), Err(SendError(10)));
+        assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+
+        // 一毫秒取不完一百万条
+        let (sender, receiver) = channel();
+        for i in 0..1_000_000 {
+            sender.send(i).unwrap();
+        }
+        let budget = Duration::from_millis(1);
+        let start = Instant::now();
+        let batch = receiver.shutdown(budget);
+        assert!(start.elapsed() < budget + Duration::from_millis(500));
+        assert!(batch.len() < 1_000_000);
+        // 取出来的是队头的那一段，剩下的还在队列里
+        assert!(batch.iter().copied().eq(0..batch.len()));
+        assert_eq!(sender.send(0), Err(SendError(0)));
+        assert_eq!(receiver.receive(), Ok(batch.len()));
+    }
+
+    #[test]
+    fn windowed_channel_blocks_until_ack() {
+        let mut channel = WindowedChannel::new(2);
+        let (sender, mut receiver) = channel.split();
+        let sent = AtomicUsize::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..4 {
+                    sender.send(i);
+                    sent.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            while sent.load(Ordering::SeqCst) < 2 {
+                thread::yield_now();
+            }
+            // 窗口满了，第三条发不出去
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(sent.load(Ordering::SeqCst), 2);
+            assert_eq!(receiver.receive_batch(), [0, 1]);
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(sent.load(Ordering::SeqCst), 2);
+            receiver.ack_batch();
+            let mut rest = receiver.receive_batch();
+            if rest.len() < 2 {
+                receiver.ack_batch();
+                rest.extend(receiver.receive_batch());
+            }
+            assert_eq!(rest, [2, 3]);
+            receiver.ack_batch();
+        });
+        assert_eq!(sent.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn bounded_send_timeout_works() {
+        let channel = BoundedChannel::with_capacity(1);
+        channel.send(1);
+        assert_eq!(
+            channel.send_timeout(2, Duration::from_millis(20)),
+            Err(SendTimeoutError::Timeout(2))
+        );
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                assert_eq!(channel.receive(), 1);
+            });
+            assert_eq!(channel.send_timeout(3, Duration::from_millis(500)), Ok(()));
+        });
+        assert_eq!(channel.receive(), 3);
+    }
+
+    #[test]
+    fn peek_works() {
+        let channel = Channel::new();
+        assert_eq!(channel.peek(|s: &String| s.len()), None);
+        channel.send(String::from("head"));
+        assert_eq!(channel.peek(|s| s.clone()).as_deref(), Some("head"));
+        assert_eq!(channel.peek(|s| s.len()), Some(4));
+        assert_eq!(channel.receive(), "head");
+    }
+
+    #[test]
+    fn conflating_channel_keeps_latest_per_key() {
+        let channel = ConflatingChannel::new();
+        channel.send("A", 1);
+        channel.send("B", 2);
+        channel.send("A", 3);
+        assert_eq!(channel.receive(), ("A", 3));
+        assert_eq!(channel.receive(), ("B", 2));
+        assert!(channel.state.lock().unwrap().values.is_empty());
+    }
+
+    #[test]
+    fn send_all_works() {
+        let channel = Channel::new();
+        thread::scope(|s| {
+            let t = s.spawn(|| {
+                // 所有消息是在一次加锁里放进去的，第一次receive就能看到后面排着的999条
+                let (first, lag) = channel.recv_with_lag();
+                let mut received = vec![first];
+                received.extend((0..999).map(|_| channel.receive()));
+                (lag, received)
+            });
+            channel.send_all((0..1000).collect::<Vec<_>>());
+            let (lag, received) = t.join().unwrap();
+            assert_eq!(lag, 999);
+            assert!(received.into_iter().eq(0..1000));
+        });
+    }
+
+    #[test]
+    fn send_all_locks_once() {
+        let channel = Channel::new();
+        let before = channel.queue.lock_count();
+        channel.send_all(0..1000);
+        assert_eq!(channel.queue.lock_count() - before, 1);
+        assert_eq!(channel.len(), 1000);
+        // 对比：逐条send每条都要加一次锁
+        let before = channel.queue.lock_count();
+        for i in 0..10 {
+            channel.send(i);
+        }
+        assert_eq!(channel.queue.lock_count() - before, 10);
+    }
+
+    #[test]
+    fn priority_channel_orders_by_priority() {
+        let channel = PriorityChannel::new();
+        channel.send("low", 1);
+        channel.send("high", 5);
+        channel.send("mid", 3);
+        assert_eq!(channel.receive(), "high");
+        assert_eq!(channel.receive(), "mid");
+        assert_eq!(channel.receive(), "low");
+    }
+
+    #[test]
+    fn priority_channel_ties_are_fifo() {
+        let channel = PriorityChannel::new();
+        for i in 0..5 {
+            channel.send(i, 2);
+        }
+        channel.send(99, 3);
+        assert_eq!(channel.receive(), 99);
+        for i in 0..5 {
+            assert_eq!(channel.receive(), i);
+        }
+    }
+
+    #[test]
+    fn manual_clock_drives_receive_timeout() {
+        let clock = Arc::new(ManualClock::new());
+        let channel = Channel::<i32>::with_clock(clock.clone());
+        // 不知道另一个线程什么时候开始计时，所以一直拨到它返回，
+        // 返回的时候拨过的时间一定不少于timeout
+        let advance_until_done = |finished: &dyn Fn() -> bool| {
+            let mut advanced = Duration::ZERO;
+            while !finished() {
+                clock.advance(Duration::from_secs(600));
+                advanced += Duration::from_secs(600);
+                thread::yield_now();
+            }
+            advanced
+        };
+        thread::scope(|s| {
+            let t = s.spawn(|| channel.receive_timeout(Duration::from_secs(3600)));
+            assert!(advance_until_done(&|| t.is_finished()) >= Duration::from_secs(3600));
+            assert_eq!(t.join().unwrap(), Err(RecvTimeoutError::Timeout));
+        });
+
+        let heartbeat = HeartbeatReceiver::new(&channel, Duration::from_secs(1200));
+        thread::scope(|s| {
+            let t = s.spawn(|| heartbeat.recv_with_heartbeat());
+            assert!(advance_until_done(&|| t.is_finished()) >= Duration::from_secs(1200));
+            assert_eq!(t.join().unwrap(), Err(Heartbeat::Stalled));
+        });
+
+        let bounded = BoundedChannel::with_capacity_and_clock(1, clock.clone());
+        bounded.send(0);
+        thread::scope(|s| {
+            let t = s.spawn(|| bounded.send_timeout(1, Duration::from_secs(3600)));
+            assert!(advance_until_done(&|| t.is_finished()) >= Duration::from_secs(3600));
+            assert_eq!(t.join().unwrap(), Err(SendTimeoutError::Timeout(1)));
+        });
+
+        let ticks = AtomicUsize::new(0);
+        thread::scope(|s| {
+            let t = s.spawn(|| {
+                channel.recv_with_tick(Duration::from_secs(60), || {
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                })
+            });
+            // 只有拨动时钟才会tick
+            advance_until_done(&|| ticks.load(Ordering::Relaxed) >= 3);
+            channel.send(7);
+            assert_eq!(t.join().unwrap(), 7);
+        });
+    }
+
+    #[test]
+    fn manual_clock_drives_one_shot_receive_timeout() {
+        let clock = Arc::new(ManualClock::new());
+        let (_sender, receiver) =
+            OneShotChannelWithArc::<i32>::channel_with_clock(Some(clock.clone()));
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    clock.advance(Duration::from_secs(600));
+                    thread::yield_now();
+                }
+            });
+            // receiver只能在创建它的线程上用，所以在这里等，另一个线程拨时钟
+            assert!(receiver.receive_timeout(Duration::from_secs(3600)).is_err());
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn one_shot_try_receive_works() {
+        let channel = OneShotChannelWithPanic::new();
+        assert_eq!(channel.try_receive(), None);
+        channel.send(String::from("once"));
+        assert_eq!(channel.try_receive().as_deref(), Some("once"));
+        assert_eq!(channel.try_receive(), None);
+    }
+
+    #[test]
+    fn one_shot_receive_reports_errors() {
+        let channel = OneShotChannelWithPanic::new();
+        assert_eq!(channel.receive(), Err(OneShotError::NotReady));
+        channel.send(1);
+        assert_eq!(channel.receive(), Ok(1));
+        assert_eq!(channel.receive(), Err(OneShotError::AlreadyConsumed));
+    }
+
+    #[test]
+    fn one_shot_try_receive_reports_errors() {
+        let (sender, receiver) = OneShotChannelWithArc::channel();
+        assert_eq!(receiver.try_receive(), Err(OneShotError::NotReady));
+        sender.send(1);
+        assert_eq!(receiver.try_receive(), Ok(1));
+        assert_eq!(receiver.try_receive(), Err(OneShotError::AlreadyConsumed));
+        assert_eq!(receiver.receive(), Err(Disconnected));
+
+        let mut channel = OneShotChannelWithBorrows::new();
+        let (sender, receiver) = channel.split();
+        assert_eq!(receiver.try_receive(), Err(OneShotError::NotReady));
+        sender.send(2);
+        assert_eq!(receiver.try_receive(), Ok(2));
+        assert_eq!(receiver.try_receive(), Err(OneShotError::AlreadyConsumed));
+        assert_eq!(receiver.receive(), Err(OneShotError::AlreadyConsumed));
+    }
+
+    #[test]
+    fn one_shot_receive_races_receive() {
+        // 两个线程同时receive，一个拿到消息，另一个一定是AlreadyConsumed，不会看到NotReady
+        for _ in 0..1000 {
+            let channel = OneShotChannelWithPanic::new();
+            channel.send(1);
+            let (a, b) = thread::scope(|s| {
+                let a = s.spawn(|| channel.receive());
+                let b = s.spawn(|| channel.receive());
+                (a.join().unwrap(), b.join().unwrap())
+            });
+            match (a, b) {
+                (Ok(1), Err(OneShotError::AlreadyConsumed))
+                | (Err(OneShotError::AlreadyConsumed), Ok(1)) => {}
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "AlreadyConsumed")]
+    fn one_shot_receive_unchecked_panics() {
+        let channel = OneShotChannelWithPanic::new();
+        channel.send(1);
+        channel.receive_unchecked();
+        channel.receive_unchecked();
+    }
+
+    #[test]
+    fn one_shot_reset_allows_reuse() {
+        let mut channel = OneShotChannelWithPanic::new();
+        for word in ["first", "second"] {
+            channel.send(String::from(word));
+            assert_eq!(channel.receive_unchecked(), word);
+            channel.reset();
+        }
+        // 发了没收的消息由Drop负责释放
+        channel.send(String::from("dropped"));
+    }
+
+    #[test]
+    fn round_robin_rotates_receivers() {
+        let channel = RoundRobinChannel::new();
+        let receivers: Vec<_> = (0..3).map(|_| channel.register()).collect();
+        thread::scope(|s| {
+            let handles: Vec<_> = receivers
+                .iter()
+                .map(|r| s.spawn(move || [r.receive(), r.receive()]))
+                .collect();
+            for i in 0..6 {
+                channel.send(i);
+            }
+            let got: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            assert_eq!(got, [[0, 3], [1, 4], [2, 5]]);
+        });
+        drop(receivers);
+        assert!(channel.state.lock().unwrap().receivers.is_empty());
+    }
+
+    #[test]
+    fn one_shot_channel_with_arc_receive_blocks() {
+        let (sender, receiver) = OneShotChannelWithArc::channel();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            sender.send("hello world!");
+        });
+        // 先于send调用，靠park等待而不是自旋
+        assert_eq!(receiver.receive(), Ok("hello world!"));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn one_shot_channel_with_arc_sender_dropped() {
+        let (sender, receiver) = OneShotChannelWithArc::<i32>::channel();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(sender);
+        });
+        // 没有send就drop，receive不会一直park
+        assert_eq!(receiver.receive(), Err(Disconnected));
+        t.join().unwrap();
+
+        let (sender, receiver) = OneShotChannelWithArc::<i32>::channel();
+        drop(sender);
+        let receiver = receiver
+            .receive_timeout(Duration::from_secs(5))
+            .unwrap_err();
+        assert_eq!(receiver.receive(), Err(Disconnected));
     }
 }
@@ -0,0 +1,1612 @@
+use arc::{Arc, Weak};
+use std::{
+    cell::UnsafeCell,
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    marker::PhantomData,
+    mem::{self, ManuallyDrop, MaybeUninit},
+    ops::{Deref, DerefMut},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Condvar, Mutex, PoisonError, TryLockError, TryLockResult,
+    },
+    thread,
+    time::Instant,
+};
+
+/// 锁状态本身，SpinLock在它上面提供UnsafeCell<T>、Guard和Deref这一套。
+/// 换一个LockWord就是换一种加锁方式，其他的代码都可以共用
+///
+/// # Safety
+///
+/// lock返回之后到unlock之前，别的线程的lock必须阻塞；
+/// unlock要和下一次成功的lock形成Release/Acquire关系，否则临界区里的写对下一个持有者不可见
+pub unsafe trait LockWord: Sync {
+    fn new() -> Self;
+    fn lock(&self);
+    /// 只试一次，拿到锁返回true，和lock一样要Acquire
+    fn try_lock(&self) -> bool;
+    /// 只有持有锁的一方才能调用
+    fn unlock(&self);
+}
+
+// 退避时每轮自旋的次数翻倍，到了这个上限之后每轮都yield_now
+const MAX_BACKOFF_SPINS: u32 = 64;
+
+/// 指数退避，等别的线程放手的循环里每失败一次调用一次snooze
+struct Backoff {
+    spins: u32,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self { spins: 1 }
+    }
+
+    fn snooze(&mut self) {
+        if self.spins <= MAX_BACKOFF_SPINS {
+            for _ in 0..self.spins {
+                std::hint::spin_loop();
+            }
+            self.spins *= 2;
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+unsafe impl LockWord for AtomicBool {
+    fn new() -> Self {
+        AtomicBool::new(false)
+    }
+
+    fn lock(&self) {
+        // 没有竞争的时候和原来一样，一次swap就拿到
+        if !self.swap(true, Ordering::Acquire) {
+            return;
+        }
+        let mut backoff = Backoff::new();
+        loop {
+            backoff.snooze();
+            // 先只读，看到锁放开了再swap，等待的时候不去抢cache line的独占
+            if !self.load(Ordering::Relaxed) && !self.swap(true, Ordering::Acquire) {
+                return;
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        !self.swap(true, Ordering::Acquire)
+    }
+
+    fn unlock(&self) {
+        self.store(false, Ordering::Release);
+    }
+}
+
+/// 排队锁：先取号再等叫号，按到达顺序拿到锁，不会有线程一直抢不到
+pub struct Ticket {
+    next: AtomicU32,
+    serving: AtomicU32,
+}
+
+unsafe impl LockWord for Ticket {
+    fn new() -> Self {
+        Self {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+        }
+    }
+
+    fn lock(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            std::hint::spin_loop();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        // 只有没人排队的时候才取号，取到的号正好被叫到，不会在队里等
+        let serving = self.serving.load(Ordering::Acquire);
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    fn unlock(&self) {
+        // 只有持有锁的线程会改serving
+        self.serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+pub type TicketLock<T> = SpinLock<T, Ticket>;
+
+/// 自旋SPINS次还抢不到就开始yield_now，把CPU让给持有者。
+/// SPINS是编译期常量，0表示一直自旋，永远不yield
+pub struct Bounded<const SPINS: usize> {
+    locked: AtomicBool,
+    // 让出CPU的次数，只是统计
+    yields: AtomicU64,
+}
+
+unsafe impl<const SPINS: usize> LockWord for Bounded<SPINS> {
+    fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            yields: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) {
+        let mut spins = 0;
+        while self.locked.swap(true, Ordering::Acquire) {
+            // SPINS == 0 在编译期就确定了，不会多出分支
+            if SPINS == 0 || spins < SPINS {
+                spins += 1;
+                std::hint::spin_loop();
+            } else {
+                self.yields.fetch_add(1, Ordering::Relaxed);
+                thread::yield_now();
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        !self.locked.swap(true, Ordering::Acquire)
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// 没有给SpinLock本身加const SPINS参数：它的第二个参数已经是LockWord，怎么等锁由LockWord决定，
+/// 所以自旋上限也放在LockWord里。这样SpinLock<T>的热路径完全不变，Ticket和Adaptive也不会多出一个用不上的参数；
+/// 要限制自旋就写BoundedSpinLock<T, 16>
+pub type BoundedSpinLock<T, const SPINS: usize> = SpinLock<T, Bounded<SPINS>>;
+
+// contention到了这个值就不再自旋，改成在Mutex/Condvar上睡眠
+const HOT_THRESHOLD: u32 = 8;
+const MAX_CONTENTION: u32 = 16;
+
+/// 平时和AtomicBool一样自旋，持续抢不到锁的时候退化成在std的Mutex上睡眠，竞争消退之后再回到自旋。
+/// contention是最近加锁情况的粗略统计：抢锁失败加一，直接拿到减一
+pub struct Adaptive {
+    locked: AtomicBool,
+    contention: AtomicU32,
+    sleepers: AtomicU32,
+    parked: Mutex<()>,
+    unlocked: Condvar,
+}
+
+unsafe impl LockWord for Adaptive {
+    fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            contention: AtomicU32::new(0),
+            sleepers: AtomicU32::new(0),
+            parked: Mutex::new(()),
+            unlocked: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) {
+        if !self.locked.swap(true, Ordering::Acquire) {
+            let n = self.contention.load(Ordering::Relaxed);
+            if n > 0 {
+                // 只是统计，并发的更新丢一两次没关系
+                self.contention.store(n - 1, Ordering::Relaxed);
+            }
+            return;
+        }
+        let n = self.contention.load(Ordering::Relaxed);
+        self.contention
+            .store((n + 1).min(MAX_CONTENTION), Ordering::Relaxed);
+        if n + 1 < HOT_THRESHOLD {
+            while self.locked.swap(true, Ordering::Acquire) {
+                std::hint::spin_loop();
+            }
+            return;
+        }
+        let mut g = self.parked.lock().unwrap();
+        // sleepers的加一和locked的检查都是SeqCst，和unlock里的 store -> load sleepers 配对：
+        // 要么这里看到锁已经放开，要么unlock看到有人在睡，不会丢失唤醒
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        while self.locked.swap(true, Ordering::SeqCst) {
+            g = self.unlocked.wait(g).unwrap();
+        }
+        self.sleepers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn try_lock(&self) -> bool {
+        // 不睡眠也不更新contention，失败了调用方自己决定干什么
+        !self.locked.swap(true, Ordering::Acquire)
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            // 拿着parked的锁notify，睡眠的一方在检查locked和wait之间一直持有这把锁
+            let _g = self.parked.lock().unwrap();
+            self.unlocked.notify_one();
+        }
+    }
+}
+
+pub type AdaptiveLock<T> = SpinLock<T, Adaptive>;
+
+pub struct SpinLock<T, L: LockWord = AtomicBool> {
+    locked: L,
+    value: UnsafeCell<T>,
+    // 打开之后guard drop的时候会把这次持有了多久记进max_hold
+    track_hold: bool,
+    max_hold: AtomicU64,
+    // 有线程拿着guard的时候panic了，数据可能只改了一半
+    poisoned: AtomicBool,
+    // lock_local记下的持有者所在节点
+    #[cfg(feature = "numa-hint")]
+    holder_node: AtomicU32,
+}
+
+#[cfg(feature = "numa-hint")]
+thread_local! {
+    static PREFERRED_NODE: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+}
+
+/// 告诉lock_local当前线程跑在哪个NUMA节点上，只是提示，不会去绑核
+#[cfg(feature = "numa-hint")]
+pub fn set_preferred_node(node: u32) {
+    PREFERRED_NODE.with(|n| n.set(node));
+}
+
+unsafe impl<T, L: LockWord> Sync for SpinLock<T, L> where T: Send {}
+
+// new放在默认LockWord的impl里，SpinLock::new(x)才推导得出L
+impl<T> SpinLock<T> {
+    pub fn new(value: T) -> Self {
+        Self::with_lock_word(value)
+    }
+
+    /// 调优用：记录每次持有锁的时长，通过max_hold_nanos找出过长的临界区。
+    /// 每次加锁会多读一次时钟
+    pub fn with_hold_tracking(value: T) -> Self {
+        Self {
+            track_hold: true,
+            ..Self::new(value)
+        }
+    }
+
+    /// 最常见的用法就是 Arc<SpinLock<T>>，这里直接返回两份共享的句柄，可以分别交给两个线程
+    pub fn shared_pair(value: T) -> (Arc<SpinLock<T>>, Arc<SpinLock<T>>) {
+        let a = Arc::new(SpinLock::new(value));
+        (a.clone(), a)
+    }
+
+    /// 和lock一样，但是抢不到锁的时候看一下持有者在哪个节点：
+    /// 在别的节点上就yield，把CPU让给本节点的线程，不去远程的cache line上自旋。
+    /// 节点只影响退避方式，不影响互斥
+    #[cfg(feature = "numa-hint")]
+    pub fn lock_local(&self) -> Result<Guard<'_, T>, PoisonError<Guard<'_, T>>> {
+        let node = PREFERRED_NODE.with(|n| n.get());
+        while self.locked.swap(true, Ordering::Acquire) {
+            if self.holder_node.load(Ordering::Relaxed) == node {
+                std::hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+        self.holder_node.store(node, Ordering::Relaxed);
+        self.poison_check(self.guard())
+    }
+}
+
+impl<T, L: LockWord> SpinLock<T, L> {
+    /// 用别的LockWord构造，比如 TicketLock::with_lock_word(x)
+    pub fn with_lock_word(value: T) -> Self {
+        Self {
+            locked: L::new(),
+            value: UnsafeCell::new(value),
+            track_hold: false,
+            max_hold: AtomicU64::new(0),
+            poisoned: AtomicBool::new(false),
+            #[cfg(feature = "numa-hint")]
+            holder_node: AtomicU32::new(0),
+        }
+    }
+
+    /// 目前为止持有锁最久的一次用了多少纳秒，没有打开记录的时候一直是0
+    pub fn max_hold_nanos(&self) -> u64 {
+        self.max_hold.load(Ordering::Relaxed)
+    }
+
+    /// 这里返回Guard是一个好的pattern，避免了生命周期的干扰
+    /// 如果这里返回的 &mut T,那么就会导致 mut T和self同生命周期，unlock方法就会要求unsafe了
+    ///
+    /// 之前有线程拿着guard panic了的话返回PoisonError，里面还是拿到的guard，
+    /// 确认数据没问题可以用into_inner继续用
+    pub fn lock(&self) -> Result<Guard<'_, T, L>, PoisonError<Guard<'_, T, L>>> {
+        self.locked.lock();
+        self.poison_check(self.guard())
+    }
+
+    /// 只试一次，锁被占着就返回WouldBlock，不自旋。
+    /// 和lock一样检查中毒，中毒了返回Poisoned，里面还是拿到的guard
+    pub fn try_lock(&self) -> TryLockResult<Guard<'_, T, L>> {
+        if !self.locked.try_lock() {
+            return Err(TryLockError::WouldBlock);
+        }
+        Ok(self.poison_check(self.guard())?)
+    }
+
+    /// 只是一个快照，别的线程随时可能让它中毒
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    fn poison_check<G>(&self, guard: G) -> Result<G, PoisonError<G>> {
+        poison_check(&self.poisoned, guard)
+    }
+
+    /// 调用之前已经拿到了锁
+    fn guard(&self) -> Guard<'_, T, L> {
+        Guard {
+            lock: self,
+            acquired: self.track_hold.then(Instant::now),
+            _no_send: PhantomData,
+        }
+    }
+
+    /// 在一次加锁里做完一批操作，很多很小的操作合并起来可以减少抢锁的次数。
+    /// 锁中毒了就panic
+    pub fn batch<R>(&self, ops: impl FnOnce(&mut T) -> R) -> R {
+        ops(&mut self.lock().unwrap())
+    }
+
+    /// 加一次锁把所有元素放进去。注意迭代器是在锁里被消费的，不要在里面做耗时的事情。
+    /// 锁中毒了就panic
+    pub fn extend<X>(&self, items: impl IntoIterator<Item = X>)
+    where
+        T: Extend<X>,
+    {
+        self.lock().unwrap().extend(items);
+    }
+
+    /// 初始化完之后数据就不会再变了，拿走锁的所有权(证明没有guard存在)，
+    /// 把T放进Arc里，之后的共享就不用再加锁了
+    pub fn freeze(self) -> Arc<T> {
+        Arc::new(self.value.into_inner())
+    }
+
+
+    // pub fn unlock(&self) {
+    //     self.locked.store(false, Ordering::Release);
+    // }
+
+    // Safety: The &mut T from lock() must be gone!
+    // (And no cheating by keeping reference to fields of that T around!)
+    // pub unsafe fn unlock(&self) {
+    //     self.locked.store(false, Ordering::Release);
+    // }
+}
+
+/// 不用的话锁马上就解开了，所以是#[must_use]:
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// let lock = spin_lock::SpinLock::new(0);
+/// lock.lock().unwrap();
+/// ```
+///
+/// 锁应该由加锁的线程来释放，所以Guard不能Send，不能move到别的线程里:
+///
+/// ```compile_fail,E0277
+/// let lock: &'static _ = Box::leak(Box::new(spin_lock::SpinLock::new(0)));
+/// let guard = lock.lock().unwrap();
+/// std::thread::spawn(move || drop(guard));
+/// ```
+#[must_use = "if unused the SpinLock will immediately unlock"]
+pub struct Guard<'a, T, L: LockWord = AtomicBool> {
+    lock: &'a SpinLock<T, L>,
+    acquired: Option<Instant>,
+    // 锁应该由加锁的线程来释放，marker type 让Guard不能Send
+    _no_send: PhantomData<*const ()>,
+}
+
+// PhantomData<*const ()> 也去掉了Sync，共享&Guard只会拿到&T，所以T: Sync就可以
+unsafe impl<T, L: LockWord> Sync for Guard<'_, T, L> where T: Sync {}
+
+impl<'a, T, L: LockWord> Deref for Guard<'a, T, L> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T, L: LockWord> DerefMut for Guard<'a, T, L> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T, L: LockWord> Drop for Guard<'a, T, L> {
+    fn drop(&mut self) {
+        record_hold(&self.lock.max_hold, self.acquired);
+        poison_if_panicking(&self.lock.poisoned);
+        self.lock.locked.unlock();
+    }
+}
+
+/// 拼日志缓冲区的时候可以直接`write!(guard, ...)`，不用先解引用
+impl<L: LockWord> fmt::Write for Guard<'_, String, L> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+impl<'a, T, L: LockWord> Guard<'a, T, L> {
+    /// 把guard缩小到T的某一部分，锁不会被释放。
+    /// 和std的MutexGuard::map一样写成关联函数，避免和T自己的map混淆
+    pub fn map<U>(guard: Self, f: impl FnOnce(&mut T) -> &mut U) -> MappedGuard<'a, U, L> {
+        match Self::try_map(guard, |v| Ok::<_, ()>(f(v))) {
+            Ok(mapped) => mapped,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// 投影可能失败的版本，失败的时候把原来的guard和错误一起还回来，期间锁一直持有
+    pub fn try_map<U, E>(
+        guard: Self,
+        f: impl FnOnce(&mut T) -> Result<&mut U, E>,
+    ) -> Result<MappedGuard<'a, U, L>, (Self, E)> {
+        let lock = guard.lock;
+        let guard_acquired = guard.acquired;
+        // Safety: The guard guarantees we've exclusively locked the lock,
+        // and the reference can't outlive 'a.
+        let value = unsafe { &mut *lock.value.get() };
+        match f(value) {
+            Ok(value) => {
+                // 锁的所有权转移给MappedGuard，不能让guard的drop解锁
+                mem::forget(guard);
+                Ok(MappedGuard {
+                    locked: &lock.locked,
+                    max_hold: &lock.max_hold,
+                    poisoned: &lock.poisoned,
+                    acquired: guard_acquired,
+                    value,
+                    _no_send: PhantomData,
+                })
+            }
+            Err(e) => Err((guard, e)),
+        }
+    }
+
+    /// 把guard拆成指向两个不相交字段的guard，方便把&mut A和&mut B分别传给不同的函数。
+    /// 两个FieldGuard共享同一把锁，都drop之后才解锁；它们不能Send，只能留在加锁的线程里
+    pub fn split_fields<A, B>(
+        guard: Self,
+        f: impl FnOnce(&mut T) -> (&mut A, &mut B),
+    ) -> (FieldGuard<'a, A, L>, FieldGuard<'a, B, L>) {
+        let lock = guard.lock;
+        // f panic的时候guard还活着，会负责中毒和解锁
+        // Safety: The guard guarantees we've exclusively locked the lock,
+        // and the references can't outlive 'a.
+        let (a, b) = f(unsafe { &mut *lock.value.get() });
+        let unlock = Rc::new(FieldUnlock {
+            locked: &lock.locked,
+            max_hold: &lock.max_hold,
+            poisoned: &lock.poisoned,
+            acquired: guard.acquired,
+        });
+        // 锁的所有权转移给共享的FieldUnlock，不能让guard的drop解锁
+        mem::forget(guard);
+        (
+            FieldGuard {
+                value: a,
+                _unlock: unlock.clone(),
+            },
+            FieldGuard {
+                value: b,
+                _unlock: unlock,
+            },
+        )
+    }
+}
+
+/// 和Guard一样是#[must_use]:
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// use spin_lock::{Guard, SpinLock};
+/// let lock = SpinLock::new((0, 1));
+/// Guard::map(lock.lock().unwrap(), |v| &mut v.0);
+/// ```
+///
+/// 也不能Send:
+///
+/// ```compile_fail,E0277
+/// use spin_lock::{Guard, SpinLock};
+/// let lock: &'static _ = Box::leak(Box::new(SpinLock::new((0, 1))));
+/// let guard = Guard::map(lock.lock().unwrap(), |v| &mut v.0);
+/// std::thread::spawn(move || drop(guard));
+/// ```
+#[must_use = "if unused the SpinLock will immediately unlock"]
+pub struct MappedGuard<'a, U, L: LockWord = AtomicBool> {
+    locked: &'a L,
+    max_hold: &'a AtomicU64,
+    poisoned: &'a AtomicBool,
+    acquired: Option<Instant>,
+    value: &'a mut U,
+    // 和Guard一样不能Send
+    _no_send: PhantomData<*const ()>,
+}
+
+unsafe impl<U, L: LockWord> Sync for MappedGuard<'_, U, L> where U: Sync {}
+
+impl<U, L: LockWord> Deref for MappedGuard<'_, U, L> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<U, L: LockWord> DerefMut for MappedGuard<'_, U, L> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value
+    }
+}
+
+impl<U, L: LockWord> Drop for MappedGuard<'_, U, L> {
+    fn drop(&mut self) {
+        record_hold(self.max_hold, self.acquired);
+        poison_if_panicking(self.poisoned);
+        self.locked.unlock();
+    }
+}
+
+// 拿着锁读poisoned，和设置它的那次unlock之间有Release/Acquire，所以Relaxed就够了
+fn poison_check<G>(poisoned: &AtomicBool, guard: G) -> Result<G, PoisonError<G>> {
+    if poisoned.load(Ordering::Relaxed) {
+        Err(PoisonError::new(guard))
+    } else {
+        Ok(guard)
+    }
+}
+
+/// 在解锁之前调用，这样下一个拿到锁的线程一定能看到
+fn poison_if_panicking(poisoned: &AtomicBool) {
+    if thread::panicking() {
+        poisoned.store(true, Ordering::Relaxed);
+    }
+}
+
+/// 在解锁之前调用，acquired是None说明这把锁没有打开记录
+fn record_hold(max_hold: &AtomicU64, acquired: Option<Instant>) {
+    if let Some(acquired) = acquired {
+        let nanos = acquired.elapsed().as_nanos().try_into().unwrap_or(u64::MAX);
+        max_hold.fetch_max(nanos, Ordering::Relaxed);
+    }
+}
+
+/// split_fields拆出来的guard。Rc让它既不是Send也不是Sync:
+///
+/// ```compile_fail,E0277
+/// use spin_lock::{Guard, SpinLock};
+/// let lock: &'static _ = Box::leak(Box::new(SpinLock::new((0, 1))));
+/// let (a, _b) = Guard::split_fields(lock.lock().unwrap(), |v| (&mut v.0, &mut v.1));
+/// std::thread::spawn(move || drop(a));
+/// ```
+#[must_use = "if unused the SpinLock may unlock immediately"]
+pub struct FieldGuard<'a, X, L: LockWord = AtomicBool> {
+    value: &'a mut X,
+    // 最后一个FieldGuard drop的时候解锁
+    _unlock: Rc<FieldUnlock<'a, L>>,
+}
+
+/// 几个FieldGuard共享的解锁句柄，不指向任何数据
+struct FieldUnlock<'a, L: LockWord> {
+    locked: &'a L,
+    max_hold: &'a AtomicU64,
+    poisoned: &'a AtomicBool,
+    acquired: Option<Instant>,
+}
+
+impl<L: LockWord> Drop for FieldUnlock<'_, L> {
+    fn drop(&mut self) {
+        record_hold(self.max_hold, self.acquired);
+        poison_if_panicking(self.poisoned);
+        self.locked.unlock();
+    }
+}
+
+impl<X, L: LockWord> Deref for FieldGuard<'_, X, L> {
+    type Target = X;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<X, L: LockWord> DerefMut for FieldGuard<'_, X, L> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value
+    }
+}
+
+// RwSpinLock::state 的这个值表示被写锁占着，其他非0的值是读者的个数
+const WRITE_LOCKED: usize = usize::MAX;
+
+/// 读多写少的时候用：多个读者可以同时持有，写者独占。
+/// 注意new出来的锁是偏向读者的：写者只有在state回到0的时候才能拿到锁，读者源源不断的话写者会一直饿着。
+/// 用new_writer_biased的话，有写者在等的时候新的读者不进来，写者一定能等到。
+/// 代价是同一个线程拿着读锁再去read可能会死锁(中间有写者在等)。
+/// 和std的RwLock一样，只有写者panic才会中毒，读者只能看不能改，panic了数据也是完整的
+pub struct RwSpinLock<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<T>,
+    poisoned: AtomicBool,
+    writer_biased: bool,
+    // 正在write里等的写者个数，只有writer_biased的时候才用
+    writers_waiting: AtomicUsize,
+}
+
+// 多个读者会同时拿到&T，所以还要求T: Sync
+unsafe impl<T> Sync for RwSpinLock<T> where T: Send + Sync {}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+            poisoned: AtomicBool::new(false),
+            writer_biased: false,
+            writers_waiting: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn new_writer_biased(value: T) -> Self {
+        let mut lock = Self::new(value);
+        lock.writer_biased = true;
+        lock
+    }
+
+    /// 只是一个快照，别的线程随时可能让它中毒
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// 之前有写者拿着锁panic了的话返回PoisonError，into_inner还是可以拿到guard
+    pub fn read(&self) -> Result<ReadGuard<'_, T>, PoisonError<ReadGuard<'_, T>>> {
+        let mut n = self.state.load(Ordering::Relaxed);
+        loop {
+            // 写锁占着，或者读者多到再加一就和WRITE_LOCKED撞上了，
+            // 或者偏向写者的时候有写者在等
+            if n >= WRITE_LOCKED - 1
+                || (self.writer_biased && self.writers_waiting.load(Ordering::Relaxed) > 0)
+            {
+                std::hint::spin_loop();
+                n = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+            // Acquire matches the Release in WriteGuard::drop.
+            match self
+                .state
+                .compare_exchange_weak(n, n + 1, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    let guard = ReadGuard {
+                        lock: self,
+                        _no_send: PhantomData,
+                    };
+                    return poison_check(&self.poisoned, guard);
+                }
+                Err(e) => n = e,
+            }
+        }
+    }
+
+    /// 和read一样，中毒了返回PoisonError
+    pub fn write(&self) -> Result<WriteGuard<'_, T>, PoisonError<WriteGuard<'_, T>>> {
+        if self.writer_biased {
+            self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        }
+        // Acquire matches the Release in both guards' drop.
+        while self
+            .state
+            .compare_exchange_weak(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+        if self.writer_biased {
+            self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+        let guard = WriteGuard {
+            lock: self,
+            _no_send: PhantomData,
+        };
+        poison_check(&self.poisoned, guard)
+    }
+}
+
+/// 不用的话读锁马上就放掉了:
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// let lock = spin_lock::RwSpinLock::new(0);
+/// lock.read().unwrap();
+/// ```
+#[must_use = "if unused the RwSpinLock will immediately unlock"]
+pub struct ReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+    // 和Guard一样，由加锁的线程来释放
+    _no_send: PhantomData<*const ()>,
+}
+
+unsafe impl<T> Sync for ReadGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: Only readers hold the lock, nobody can write.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release: 最后一个读者离开之后，写者要看到读者读完了
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// 不用的话写锁马上就放掉了:
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// let lock = spin_lock::RwSpinLock::new(0);
+/// lock.write().unwrap();
+/// ```
+#[must_use = "if unused the RwSpinLock will immediately unlock"]
+pub struct WriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+    _no_send: PhantomData<*const ()>,
+}
+
+unsafe impl<T> Sync for WriteGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: The write lock is exclusive.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: The write lock is exclusive.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> WriteGuard<'a, T> {
+    /// 写完之后原地变成读锁，中间不放锁，别的写者插不进来，其他读者可以马上进来
+    pub fn downgrade(self) -> ReadGuard<'a, T> {
+        let lock = self.lock;
+        // 不走WriteGuard::drop，不然锁会先被放掉
+        std::mem::forget(self);
+        // Release: 之后进来的读者要看到刚才写的内容
+        lock.state.store(1, Ordering::Release);
+        ReadGuard {
+            lock,
+            _no_send: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        poison_if_panicking(&self.lock.poisoned);
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+/// 简单的对象池：空闲的资源放在一个SpinLock<Vec<T>>里，acquire拿走一个独占使用，
+/// guard drop的时候放回去给下一次acquire复用。
+/// channel那个crate是bin，这里依赖不了，所以空闲列表直接用SpinLock
+pub struct PooledSpinLock<T> {
+    free: SpinLock<Vec<T>>,
+}
+
+impl<T> PooledSpinLock<T> {
+    pub fn new(items: impl IntoIterator<Item = T>) -> Self {
+        Self {
+            free: SpinLock::new(items.into_iter().collect()),
+        }
+    }
+
+    /// 往池子里多放一个资源
+    pub fn put(&self, item: T) {
+        // 空闲列表上只有push/pop，就算中毒了里面也是完整的
+        self.free
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(item);
+    }
+
+    /// 池子空着的时候退避等别人归还，等久了就yield_now，空闲列表的锁只在pop的时候持有
+    pub fn acquire(&self) -> PooledGuard<'_, T> {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(value) = self
+                .free
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pop()
+            {
+                return PooledGuard {
+                    pool: self,
+                    value: ManuallyDrop::new(value),
+                };
+            }
+            backoff.snooze();
+        }
+    }
+}
+
+/// 不用的话资源马上就还回池子里了:
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// let pool = spin_lock::PooledSpinLock::new([0]);
+/// pool.acquire();
+/// ```
+#[must_use = "if unused the resource goes straight back to the pool"]
+pub struct PooledGuard<'a, T> {
+    pool: &'a PooledSpinLock<T>,
+    value: ManuallyDrop<T>,
+}
+
+impl<T> Deref for PooledGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for PooledGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl<T> Drop for PooledGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: value is never touched again after this.
+        let value = unsafe { ManuallyDrop::take(&mut self.value) };
+        self.pool.put(value);
+    }
+}
+
+/// 相同的值只保留一份Arc，HashMap里存Weak，这样interner本身不会让值一直活着。
+/// arc那个crate依赖不了这里，所以放在这边用SpinLock
+pub struct ArcInterner<T> {
+    map: SpinLock<InternMap<T>>,
+}
+
+struct InternMap<T> {
+    entries: HashMap<T, Weak<T>>,
+    // 条目数涨到这么多的时候才清理一次死掉的Weak，清理之后翻倍，均摊下来每次intern是O(1)
+    prune_at: usize,
+}
+
+const MIN_PRUNE_AT: usize = 16;
+
+impl<T: Eq + Hash + Clone> ArcInterner<T> {
+    pub fn new() -> Self {
+        Self {
+            map: SpinLock::new(InternMap {
+                entries: HashMap::new(),
+                prune_at: MIN_PRUNE_AT,
+            }),
+        }
+    }
+
+    /// 有相等的值还活着就返回同一个Arc，否则新建一个
+    pub fn intern(&self, value: T) -> Arc<T> {
+        // 只有insert和retain，中途panic的话map也是完整的
+        let mut map = self.map.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(arc) = map.entries.get(&value).and_then(Weak::upgrade) {
+            return arc;
+        }
+        if map.entries.len() >= map.prune_at {
+            map.entries.retain(|_, weak| weak.upgrade().is_some());
+            map.prune_at = (map.entries.len() * 2).max(MIN_PRUNE_AT);
+        }
+        let arc = Arc::new(value.clone());
+        map.entries.insert(value, Arc::downgrade(&arc));
+        arc
+    }
+}
+
+impl<T: Eq + Hash + Clone> Default for ArcInterner<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const UNINIT: u8 = 0;
+const RUNNING: u8 = 1;
+const READY: u8 = 2;
+
+/// 第一次访问的时候才初始化的值，没初始化完的时候其他线程自旋等待。
+/// init是fn指针，所以可以在static里用const fn new构造
+pub struct SpinLazy<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+    init: fn() -> T,
+}
+
+// 任意线程都可能执行init并拿到&T，所以需要T: Send + Sync
+unsafe impl<T> Sync for SpinLazy<T> where T: Send + Sync {}
+
+impl<T> SpinLazy<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        Self {
+            state: AtomicU8::new(UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            init,
+        }
+    }
+
+    pub fn get(&self) -> &T {
+        while self.state.load(Ordering::Acquire) != READY {
+            match self
+                .state
+                .compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    // init panic的时候把状态恢复成UNINIT，让别的线程可以重试，而不是永远自旋
+                    struct Reset<'a>(&'a AtomicU8);
+                    impl Drop for Reset<'_> {
+                        fn drop(&mut self) {
+                            self.0.store(UNINIT, Ordering::Release);
+                        }
+                    }
+                    let reset = Reset(&self.state);
+                    let value = (self.init)();
+                    mem::forget(reset);
+                    // Safety: Only the thread that moved the state to RUNNING writes the value.
+                    unsafe { (*self.value.get()).write(value) };
+                    self.state.store(READY, Ordering::Release);
+                }
+                Err(_) => std::hint::spin_loop(),
+            }
+        }
+        // Safety: The state is READY, so the value is initialized and never written again.
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T> Deref for SpinLazy<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.get()
+    }
+}
+
+impl<T> Drop for SpinLazy<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+/// 声明懒初始化的全局变量，不需要外部crate，一次可以声明多个:
+///
+/// ```
+/// use spin_lock::{spin_lazy_static, SpinLock};
+/// spin_lazy_static! {
+///     static GLOBAL: SpinLock<Vec<i32>> = SpinLock::new(Vec::new()) => fn global;
+///     pub static NAME: String = String::from("x");
+/// }
+/// global().lock().unwrap().push(1);
+/// assert_eq!(GLOBAL.lock().unwrap().len(), 1);
+/// assert_eq!(*NAME, "x");
+/// ```
+///
+/// 每个声明展开成一个SpinLazy的static，通过get()或者Deref访问；
+/// 写了`=> fn 名字`的话再生成一个返回&'static T的访问函数，可见性可以单独写
+#[macro_export]
+macro_rules! spin_lazy_static {
+    ($(
+        $(#[$attr:meta])*
+        $vis:vis static $name:ident: $t:ty = $init:expr $(=> $fvis:vis fn $getter:ident)?;
+    )*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::SpinLazy<$t> = $crate::SpinLazy::new(|| $init);
+            $(
+                $fvis fn $getter() -> &'static $t {
+                    $name.get()
+                }
+            )?
+        )*
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_pair_works() {
+        let (a, b) = SpinLock::shared_pair(0);
+        thread::scope(|s| {
+            s.spawn(move || {
+                for _ in 0..1000 {
+                    *a.lock().unwrap() += 1;
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    *b.lock().unwrap() += 1;
+                }
+            });
+        });
+        assert_eq!(*b.lock().unwrap(), 2000);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum State {
+        Idle,
+        Running(u32),
+    }
+
+    fn running(s: &mut State) -> Result<&mut u32, &'static str> {
+        match s {
+            State::Running(n) => Ok(n),
+            State::Idle => Err("not running"),
+        }
+    }
+
+    #[test]
+    fn try_map_works() {
+        let lock = SpinLock::new(State::Running(1));
+        let mut g = Guard::try_map(lock.lock().unwrap(), running).ok().unwrap();
+        *g += 1;
+        assert!(lock.locked.load(Ordering::Relaxed));
+        drop(g);
+        assert!(!lock.locked.load(Ordering::Relaxed));
+        assert_eq!(*lock.lock().unwrap(), State::Running(2));
+
+        let lock = SpinLock::new(State::Idle);
+        let (g, e) = Guard::try_map(lock.lock().unwrap(), running).err().unwrap();
+        assert_eq!(e, "not running");
+        // 失败的时候锁还在原来的guard手里
+        assert!(lock.locked.load(Ordering::Relaxed));
+        assert_eq!(*g, State::Idle);
+        drop(g);
+        assert!(!lock.locked.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn batch_and_extend_work() {
+        let x = SpinLock::new(Vec::new());
+        thread::scope(|s| {
+            s.spawn(|| x.extend(0..100));
+            s.spawn(|| x.extend(100..200));
+        });
+        let len = x.batch(|v| {
+            v.sort();
+            v.len()
+        });
+        assert_eq!(len, 200);
+        assert!(x.lock().unwrap().iter().copied().eq(0..200));
+    }
+
+    spin_lazy_static! {
+        static GLOBAL: SpinLock<Vec<i32>> = SpinLock::new(Vec::new()) => fn global;
+        static COUNTER: SpinLock<u32> = SpinLock::new(0);
+    }
+
+    #[test]
+    fn spin_lazy_static_works() {
+        thread::scope(|s| {
+            s.spawn(|| global().lock().unwrap().push(1));
+            s.spawn(|| GLOBAL.get().lock().unwrap().push(2));
+            s.spawn(|| *COUNTER.lock().unwrap() += 1);
+        });
+        let mut v = global().lock().unwrap().clone();
+        v.sort();
+        assert_eq!(v, [1, 2]);
+        assert!(std::ptr::eq(global(), GLOBAL.get()));
+        assert_eq!(*COUNTER.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn freeze_works() {
+        let x = SpinLock::new(Vec::new());
+        x.lock().unwrap().push(1);
+        x.lock().unwrap().push(2);
+        let frozen = x.freeze();
+        thread::scope(|s| {
+            s.spawn(|| assert_eq!(*frozen, [1, 2]));
+            s.spawn(|| assert_eq!(frozen.len(), 2));
+        });
+    }
+
+    fn assert_sync<T: Sync>() {}
+
+    // 不能Send由Guard、MappedGuard和FieldGuard上的compile_fail文档测试检查
+    #[test]
+    fn guard_is_sync() {
+        assert_sync::<Guard<'static, i32>>();
+        assert_sync::<MappedGuard<'static, i32>>();
+    }
+
+    #[test]
+    fn guard_fmt_write_works() {
+        use std::fmt::Write;
+        let lock = SpinLock::new(String::from("log:"));
+        {
+            let mut g = lock.lock().unwrap();
+            let x = 1;
+            write!(g, " x={}", x).unwrap();
+            writeln!(g, " done").unwrap();
+        }
+        assert_eq!(*lock.lock().unwrap(), "log: x=1 done\n");
+    }
+
+    #[test]
+    fn max_hold_nanos_works() {
+        let lock = SpinLock::with_hold_tracking(0);
+        let hold = std::time::Duration::from_millis(20);
+        {
+            let mut g = lock.lock().unwrap();
+            *g += 1;
+            thread::sleep(hold);
+        }
+        {
+            let _g = Guard::map(lock.lock().unwrap(), |v| v);
+        }
+        assert!(lock.max_hold_nanos() >= hold.as_nanos() as u64);
+        assert_eq!(SpinLock::new(()).max_hold_nanos(), 0);
+    }
+
+    #[test]
+    fn default_lock_word_unchanged() {
+        let lock: SpinLock<i32, AtomicBool> = SpinLock::new(0);
+        let g = lock.lock().unwrap();
+        assert!(lock.locked.load(Ordering::Relaxed));
+        drop(g);
+        assert!(!lock.locked.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn backoff_under_contention() {
+        let lock = SpinLock::new(0u64);
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *lock.lock().unwrap() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock().unwrap(), 80_000);
+        // 只是粗略地防止退避退化成长时间的空等
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn lock_is_poisoned_by_panic() {
+        let lock = SpinLock::new(vec![1]);
+        thread::scope(|s| {
+            let r = s
+                .spawn(|| {
+                    let mut g = lock.lock().unwrap();
+                    g.push(2);
+                    panic!("poison the lock");
+                })
+                .join();
+            assert!(r.is_err());
+        });
+        assert!(lock.is_poisoned());
+        let Err(e) = lock.lock() else {
+            panic!("lock should be poisoned");
+        };
+        // 还是可以拿到数据继续用
+        let mut g = e.into_inner();
+        assert_eq!(*g, [1, 2]);
+        g.push(3);
+        drop(g);
+        assert!(lock.lock().is_err());
+
+        // 映射之后的guard也会让锁中毒
+        let other = SpinLock::new(0);
+        let r = thread::scope(|s| {
+            s.spawn(|| {
+                let _g = Guard::map(other.lock().unwrap(), |v| v);
+                panic!("poison the mapped guard");
+            })
+            .join()
+        });
+        assert!(r.is_err());
+        assert!(other.is_poisoned());
+    }
+
+    #[test]
+    fn try_lock_does_not_spin() {
+        let lock = SpinLock::new(0);
+        let ticket = TicketLock::with_lock_word(0);
+        let g = lock.lock().unwrap();
+        let t = ticket.lock().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                assert!(matches!(lock.try_lock(), Err(TryLockError::WouldBlock)));
+                assert!(matches!(ticket.try_lock(), Err(TryLockError::WouldBlock)));
+            });
+        });
+        drop(g);
+        drop(t);
+        *lock.try_lock().unwrap() += 1;
+        *ticket.try_lock().unwrap() += 1;
+        assert_eq!(*lock.lock().unwrap(), 1);
+        assert_eq!(*ticket.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn ticket_lock_works() {
+        let lock = TicketLock::with_lock_word(Vec::new());
+        thread::scope(|s| {
+            for t in 0..4 {
+                let lock = &lock;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        lock.lock().unwrap().push(t * 1000 + i);
+                    }
+                });
+            }
+        });
+        let mut g = Guard::map(lock.lock().unwrap(), |v| v);
+        g.sort();
+        assert!(g.iter().copied().eq(0..4000));
+        drop(g);
+        assert_eq!(
+            lock.locked.next.load(Ordering::Relaxed),
+            lock.locked.serving.load(Ordering::Relaxed)
+        );
+    }
+
+    #[test]
+    fn ticket_try_lock_wraps_around() {
+        let lock = TicketLock::with_lock_word(0);
+        lock.locked.next.store(u32::MAX, Ordering::Relaxed);
+        lock.locked.serving.store(u32::MAX, Ordering::Relaxed);
+        // 号码到头之后绕回0，try_lock和lock都照常工作
+        *lock.try_lock().unwrap() += 1;
+        *lock.try_lock().unwrap() += 1;
+        *lock.lock().unwrap() += 1;
+        assert_eq!(lock.locked.serving.load(Ordering::Relaxed), 2);
+        assert_eq!(*lock.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn split_fields_works() {
+        fn push(v: &mut Vec<i32>) {
+            v.push(1);
+        }
+        fn append(s: &mut String) {
+            s.push('!');
+        }
+        let lock = SpinLock::new((Vec::new(), String::from("hi")));
+        let (mut v, mut s) = Guard::split_fields(lock.lock().unwrap(), |(v, s)| (v, s));
+        push(&mut v);
+        append(&mut s);
+        drop(v);
+        assert!(lock.locked.load(Ordering::Relaxed));
+        drop(s);
+        assert!(!lock.locked.load(Ordering::Relaxed));
+        assert_eq!(*lock.lock().unwrap(), (vec![1], String::from("hi!")));
+    }
+
+    #[test]
+    fn bounded_spin_lock_yields() {
+        let lock = BoundedSpinLock::<i32, 16>::with_lock_word(0);
+        let g = lock.lock().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| *lock.lock().unwrap() += 1);
+            // 锁一直被占着，自旋16次之后只能去yield
+            while lock.locked.yields.load(Ordering::Relaxed) == 0 {
+                std::hint::spin_loop();
+            }
+            drop(g);
+        });
+        assert_eq!(*lock.lock().unwrap(), 1);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *lock.lock().unwrap() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock().unwrap(), 40_001);
+
+        // SPINS为0时一直自旋，不会yield
+        let lock = BoundedSpinLock::<i32, 0>::with_lock_word(0);
+        drop(lock.lock().unwrap());
+        assert_eq!(lock.locked.yields.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn split_fields_unlocks_when_f_panics() {
+        let lock = SpinLock::new((1, 2));
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _ = Guard::split_fields(lock.lock().unwrap(), |_| -> (&mut i32, &mut i32) {
+                panic!("split failed")
+            });
+        }));
+        assert!(r.is_err());
+        // 锁已经放开并且中毒了，不会一直自旋
+        let Err(TryLockError::Poisoned(e)) = lock.try_lock() else {
+            panic!("try_lock on a poisoned lock must report it");
+        };
+        // 和lock一样，还是可以拿到guard
+        assert_eq!(*e.into_inner(), (1, 2));
+        assert!(lock.lock().is_err());
+    }
+
+    #[test]
+    fn adaptive_lock_under_contention() {
+        let lock = AdaptiveLock::with_lock_word(0u64);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..20_000 {
+                        *lock.lock().unwrap() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock().unwrap(), 160_000);
+        assert_eq!(lock.locked.sleepers.load(Ordering::Relaxed), 0);
+        // 没有竞争之后慢慢回到自旋
+        for _ in 0..MAX_CONTENTION {
+            drop(lock.lock().unwrap());
+        }
+        assert_eq!(lock.locked.contention.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "numa-hint")]
+    fn lock_local_is_mutually_exclusive() {
+        let lock = SpinLock::new(0);
+        thread::scope(|s| {
+            for t in 0..4 {
+                let lock = &lock;
+                s.spawn(move || {
+                    set_preferred_node(t % 2);
+                    for i in 0..10_000 {
+                        // 混着用普通的lock，节点提示不影响互斥
+                        let mut g = if i % 3 == 0 {
+                            lock.lock().unwrap()
+                        } else {
+                            lock.lock_local().unwrap()
+                        };
+                        *g += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock().unwrap(), 40_000);
+    }
+
+    #[test]
+    fn rw_spin_lock_readers_coexist() {
+        let lock = RwSpinLock::new(vec![1, 2, 3]);
+        let a = lock.read().unwrap();
+        let b = lock.read().unwrap();
+        assert_eq!(lock.state.load(Ordering::Relaxed), 2);
+        assert_eq!(a.len() + b.len(), 6);
+        drop((a, b));
+        lock.write().unwrap().push(4);
+        assert_eq!(*lock.read().unwrap(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rw_spin_lock_writer_excludes_readers() {
+        let lock = RwSpinLock::new((0u64, 0u64));
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let mut g = lock.write().unwrap();
+                        g.0 += 1;
+                        g.1 += 1;
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        // 写者改到一半的时候读者进不来
+                        let g = lock.read().unwrap();
+                        assert_eq!(g.0, g.1);
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.read().unwrap(), (20_000, 20_000));
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn rw_spin_lock_writer_biased_writer_gets_in() {
+        let lock = RwSpinLock::new_writer_biased(0);
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            // 读者一个接一个地重叠着拿读锁，state几乎不会回到0
+            for _ in 0..3 {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        let _g = lock.read().unwrap();
+                        thread::yield_now();
+                    }
+                });
+            }
+            s.spawn(|| {
+                thread::sleep(std::time::Duration::from_millis(20));
+                *lock.write().unwrap() = 1;
+                done.store(true, Ordering::Relaxed);
+            });
+        });
+        assert_eq!(*lock.read().unwrap(), 1);
+        assert_eq!(lock.writers_waiting.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn rw_spin_lock_downgrade_lets_readers_in() {
+        let lock = RwSpinLock::new(0);
+        let written = AtomicBool::new(false);
+        let mut w = lock.write().unwrap();
+        *w = 1;
+        let r = w.downgrade();
+        thread::scope(|s| {
+            // 别的读者可以马上拿到读锁，看到的是写完的值
+            s.spawn(|| assert_eq!(*lock.read().unwrap(), 1))
+                .join()
+                .unwrap();
+            let writer = s.spawn(|| {
+                *lock.write().unwrap() = 2;
+                written.store(true, Ordering::Relaxed);
+            });
+            thread::sleep(std::time::Duration::from_millis(50));
+            // 降级后的读锁还在，写者进不来
+            assert!(!written.load(Ordering::Relaxed));
+            assert_eq!(*r, 1);
+            drop(r);
+            writer.join().unwrap();
+        });
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn rw_spin_lock_is_poisoned_by_writer_panic() {
+        let lock = RwSpinLock::new(0);
+        // 读者panic不会中毒
+        let r = thread::scope(|s| {
+            s.spawn(|| {
+                let _g = lock.read().unwrap();
+                panic!();
+            })
+            .join()
+        });
+        assert!(r.is_err());
+        assert!(!lock.is_poisoned());
+
+        let r = thread::scope(|s| {
+            s.spawn(|| {
+                let mut g = lock.write().unwrap();
+                *g = 1;
+                panic!();
+            })
+            .join()
+        });
+        assert!(r.is_err());
+        assert!(lock.is_poisoned());
+        let Err(e) = lock.read() else {
+            panic!("read succeeded on a poisoned lock");
+        };
+        assert_eq!(*e.into_inner(), 1);
+        let Err(e) = lock.write() else {
+            panic!("write succeeded on a poisoned lock");
+        };
+        drop(e);
+        // 锁本身已经放开了
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn pooled_resource_is_recycled() {
+        let pool = PooledSpinLock::new([Vec::with_capacity(16)]);
+        let ptr = {
+            let mut buf = pool.acquire();
+            buf.extend_from_slice(b"hello");
+            buf.as_ptr()
+        };
+        let mut buf = pool.acquire();
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(*buf, b"hello");
+        buf.clear();
+        drop(buf);
+        assert_eq!(pool.free.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn pooled_acquire_waits_for_return() {
+        let pool = PooledSpinLock::new([0]);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        // 池子里只有一个，其他线程都在acquire里退避等待
+                        *pool.acquire() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*pool.acquire(), 400);
+    }
+
+    #[test]
+    fn intern_dedups() {
+        let interner = ArcInterner::new();
+        let a = interner.intern(String::from("hello"));
+        let b = interner.intern(String::from("hello"));
+        let c = interner.intern(String::from("world"));
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &c));
+
+        // 所有Arc都drop之后，下次intern会新建
+        drop((a, b));
+        let d = interner.intern(String::from("hello"));
+        assert_eq!(*d, "hello");
+    }
+
+    #[test]
+    fn intern_prunes_amortized() {
+        let interner = ArcInterner::new();
+        for i in 0..1000 {
+            drop(interner.intern(i));
+        }
+        // 死掉的条目攒到一定数量才清理，不会无限增长
+        let map = interner.map.lock().unwrap();
+        assert!(map.entries.len() <= map.prune_at);
+        assert!(map.prune_at <= 2 * MIN_PRUNE_AT);
+    }
+}
@@ -1,85 +1,16 @@
-use std::{
-    cell::UnsafeCell,
-    ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
-    thread,
-};
-
-pub struct SpinLock<T> {
-    locked: AtomicBool,
-    value: UnsafeCell<T>,
-}
-
-unsafe impl<T> Sync for SpinLock<T> where T: Send {}
-
-impl<T> SpinLock<T> {
-    pub fn new(value: T) -> Self {
-        Self {
-            locked: AtomicBool::new(false),
-            value: UnsafeCell::new(value),
-        }
-    }
-
-    /// 这里返回Guard是一个好的pattern，避免了生命周期的干扰
-    /// 如果这里返回的 &mut T,那么就会导致 mut T和self同生命周期，unlock方法就会要求unsafe了
-    pub fn lock(&self) -> Guard<T> {
-        while self.locked.swap(true, Ordering::Acquire) {
-            std::hint::spin_loop();
-        }
-        Guard { lock: &self }
-    }
-
-
-
-    // pub fn unlock(&self) {
-    //     self.locked.store(false, Ordering::Release);
-    // }
-
-    // Safety: The &mut T from lock() must be gone!
-    // (And no cheating by keeping reference to fields of that T around!)
-    // pub unsafe fn unlock(&self) {
-    //     self.locked.store(false, Ordering::Release);
-    // }
-}
-
-pub struct Guard<'a, T> {
-    lock: &'a SpinLock<T>,
-}
-
-impl<'a, T> Deref for Guard<'a, T> {
-    type Target = T;
-
-    fn deref(&self) -> &Self::Target {
-        // Safety: The very existence of this Guard
-        // guarantees we've exclusively locked the lock.
-        unsafe { &*self.lock.value.get() }
-    }
-}
-
-impl<'a, T> DerefMut for Guard<'a, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        // Safety: The very existence of this Guard
-        // guarantees we've exclusively locked the lock.
-        unsafe { &mut *self.lock.value.get() }
-    }
-}
-
-impl<'a, T> Drop for Guard<'a, T> {
-    fn drop(&mut self) {
-        self.lock.locked.store(false, Ordering::Release);
-    }
-}
+use spin_lock::SpinLock;
+use std::thread;
 
 fn main() {
     let x = SpinLock::new(Vec::new());
     thread::scope(|s| {
-        s.spawn(|| x.lock().push(1));
+        s.spawn(|| x.lock().unwrap().push(1));
         s.spawn(|| {
-            let mut g = x.lock();
+            let mut g = x.lock().unwrap();
             g.push(2);
             g.push(2);
         });
     });
-    let g = x.lock();
+    let g = x.lock().unwrap();
     assert!(g.as_slice() == [1, 2, 2] || g.as_slice() == [2, 2, 1]);
 }